@@ -9,9 +9,10 @@ pub enum OpCode {
     OpTrue,
     OpFalse,
     OpPop,
+    OpSwap,
+    OpOver,
     OpGetLocal,
     OpSetLocal,
-    OpSetConstLocal,
     OpGetGlobal,
     OpDefineGlobal,
     OpSetGlobal,
@@ -28,10 +29,16 @@ pub enum OpCode {
     OpNot,
     OpNegate,
     OpPrint,
+    OpAssert,
+    OpDefer,
+    OpDeferred,
     OpJump,
     OpJumpIfFalse,
     OpLoop,
     OpCall,
+    OpTailCall,
+    OpInvoke,
+    OpGetProperty,
     OpReturn,
 }
 
@@ -81,30 +88,37 @@ impl From<u8> for OpCode {
             2 => OpCode::OpTrue,
             3 => OpCode::OpFalse,
             4 => OpCode::OpPop,
-            5 => OpCode::OpGetLocal,
-            6 => OpCode::OpSetLocal,
-            7 => OpCode::OpSetConstLocal,
-            8 => OpCode::OpGetGlobal,
-            9 => OpCode::OpDefineGlobal,
-            10 => OpCode::OpSetGlobal,
-            11 => OpCode::OpDefineConstGlobal,
-            12 => OpCode::OpEqual,
-            13 => OpCode::OpGreater,
-            14 => OpCode::OpLess,
-            15 => OpCode::OpAdd,
-            16 => OpCode::OpSubtract,
-            17 => OpCode::OpMultiply,
-            18 => OpCode::OpDivide,
-            19 => OpCode::OpExponent,
-            20 => OpCode::OpModulus,
-            21 => OpCode::OpNot,
-            22 => OpCode::OpNegate,
-            23 => OpCode::OpPrint,
-            24 => OpCode::OpJump,
-            25 => OpCode::OpJumpIfFalse,
-            26 => OpCode::OpLoop,
-            27 => OpCode::OpCall,
-            28 => OpCode::OpReturn,
+            5 => OpCode::OpSwap,
+            6 => OpCode::OpOver,
+            7 => OpCode::OpGetLocal,
+            8 => OpCode::OpSetLocal,
+            9 => OpCode::OpGetGlobal,
+            10 => OpCode::OpDefineGlobal,
+            11 => OpCode::OpSetGlobal,
+            12 => OpCode::OpDefineConstGlobal,
+            13 => OpCode::OpEqual,
+            14 => OpCode::OpGreater,
+            15 => OpCode::OpLess,
+            16 => OpCode::OpAdd,
+            17 => OpCode::OpSubtract,
+            18 => OpCode::OpMultiply,
+            19 => OpCode::OpDivide,
+            20 => OpCode::OpExponent,
+            21 => OpCode::OpModulus,
+            22 => OpCode::OpNot,
+            23 => OpCode::OpNegate,
+            24 => OpCode::OpPrint,
+            25 => OpCode::OpAssert,
+            26 => OpCode::OpDefer,
+            27 => OpCode::OpDeferred,
+            28 => OpCode::OpJump,
+            29 => OpCode::OpJumpIfFalse,
+            30 => OpCode::OpLoop,
+            31 => OpCode::OpCall,
+            32 => OpCode::OpTailCall,
+            33 => OpCode::OpInvoke,
+            34 => OpCode::OpGetProperty,
+            35 => OpCode::OpReturn,
             ///////////////////////////////////
             //// Could create possible bug ////
             ///////////////////////////////////
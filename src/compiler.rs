@@ -1,5 +1,7 @@
 // A mutable memory location with dynamically checked borrow rules
 use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
 
 use crate::value::*;
 use crate::scanner::*;
@@ -14,6 +16,13 @@ struct Parser {
     previous: Token,
     had_error: bool,
     panic_mode: bool,
+    // '///' comments written before the current and previous tokens
+    current_doc: Option<String>,
+    previous_doc: Option<String>,
+    // How many parentheses are open, newlines inside them don't end statements
+    paren_depth: usize,
+    // The current token comes from a macro expansion instead of the scanner
+    current_expanded: bool,
 }
 
 impl Parser {
@@ -23,14 +32,24 @@ impl Parser {
                 _type : TokenType::Undefined,
                 lexeme: String::new(),
                 line: 0,
+                newline_before: false,
+                expansion: 0,
+                offset: None,
             },
             previous : Token {
                 _type : TokenType::Undefined,
                 lexeme: String::new(),
-                line: 0
+                line: 0,
+                newline_before: false,
+                expansion: 0,
+                offset: None,
             },
             had_error : false,
-            panic_mode: false
+            panic_mode: false,
+            current_doc: None,
+            previous_doc: None,
+            paren_depth: 0,
+            current_expanded: false,
         }
     }
 }
@@ -55,6 +74,52 @@ struct Local {
     _type: TokenType
 }
 
+/*
+    What the compiler knows about a global variable declared in the source
+*/
+#[derive(Clone, Copy)]
+struct GlobalInfo {
+    constant: bool,
+}
+
+/*
+    A macro declared with 'macro', its body is the tokens between its braces
+*/
+#[derive(Clone)]
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Token>,
+}
+
+/*
+    An operator declared with 'infix' or 'infixr', which calls the function declared with it
+*/
+#[derive(Clone, Copy)]
+struct DeclaredOperator {
+    precedence: Precedence,
+    right_associative: bool,
+}
+
+/*
+    A piece of compiled code taken out of a chunk so it can be emitted again somewhere else
+*/
+#[derive(Clone)]
+struct CodeSegment {
+    code: Vec<u8>,
+    lines: Vec<usize>,
+}
+
+/*
+    The code of a defer statement, along with the scope depth it is in and its number in the function,
+    which the VM uses to remember whether the statement ran
+*/
+#[derive(Clone)]
+struct Deferred {
+    scope_depth: usize,
+    index: u8,
+    code: CodeSegment,
+}
+
 #[derive(PartialEq, Clone, Copy)]
 enum FunctionType {
     TypeFunction,
@@ -69,27 +134,80 @@ struct CurrCompiler {
     locals: RefCell<Vec<Local>>,
     fun_type: FunctionType,
     scope_depth: RefCell<usize>,
+    // Code of the defer statements along with the scope depth of each, copied before every return of the function
+    // and at the end of the block the defer is in
+    defers: RefCell<Vec<Deferred>>,
+    // The number of defer statements in the function so far
+    defer_count: RefCell<usize>,
+    // Offset of the last OpCall emitted, to find calls in tail position
+    last_call: RefCell<Option<usize>>,
+    // Code of the 'ensures' clauses, copied before every return of the function
+    ensures: RefCell<Vec<CodeSegment>>,
+    // The hidden local that holds the return value while the 'ensures' clauses check it
+    result_slot: RefCell<Option<u8>>,
 }
 
 impl CurrCompiler {
     fn new(fun_type: FunctionType) -> Self{
+        /* the compiler implicitly claims stack slot zero for the VM’s own internal use, it holds the function being called */
+        let callee = Local { name: Token { _type: TokenType::Undefined, lexeme: "".to_string(), line: 0, newline_before: false, expansion: 0, offset: None }, depth: Some(0), _type: TokenType::Undefined };
         CurrCompiler {
             function: RefCell::new(Function::new(0, Chunk::new(), None)),
-            locals: RefCell::new(Vec::new()),
+            locals: RefCell::new(vec![callee]),
             fun_type: fun_type,
             scope_depth: RefCell::new(0),
+            defers: RefCell::new(Vec::new()),
+            defer_count: RefCell::new(0),
+            last_call: RefCell::new(None),
+            ensures: RefCell::new(Vec::new()),
+            result_slot: RefCell::new(None),
         }
     }
 }
 
 
+/*
+    Options that change how source code is compiled, usually set from the command line
+*/
+#[derive(Clone, Copy, Default)]
+pub struct CompilerOptions {
+    // Assertions are parsed but no code is emitted for them
+    pub strip_asserts: bool,
+    // Newlines end statements, so semicolons can be left out
+    pub optional_semicolons: bool,
+    // Using a global that isn't declared anywhere in the file is a compile error
+    pub strict: bool,
+    // 'requires' and 'ensures' clauses are parsed but no code is emitted for them
+    pub strip_contracts: bool,
+}
+
 pub struct Compiler {
+    pub options: CompilerOptions,
     parser: Parser,
     scanner: Scanner,
     rules: Vec<ParseRule>,
     // CITE: Learned to use RefCell by UncleScientist lox-bytecode repo in Github
     // CITE: https://github.com/UncleScientist/lox-bytecode
     curr_compiler: RefCell<CurrCompiler>,
+    // Globals declared so far, kept between calls to compile() like the VM keeps their values
+    globals: HashMap<String, GlobalInfo>,
+    // Globals the VM defines before running the code, like the natives
+    pub predefined_globals: HashSet<String>,
+    // In strict mode, every global declared in the file along with the predefined ones
+    strict_globals: HashSet<String>,
+    // Operators declared so far by their symbol, the function of each is a global with the symbol as its name
+    operators: HashMap<String, DeclaredOperator>,
+    macros: HashMap<String, Macro>,
+    // Tokens of macro expansions, read before the ones from the scanner, along with how many expansions deep each is
+    expanded: VecDeque<(Token, usize)>,
+    // The next token from the scanner and the doc comment before it, when it was looked at before it was needed
+    lookahead: Option<(Token, Option<String>)>,
+    // The number of the last macro expansion, every expansion gets its own
+    expansions: usize,
+    // Macro calls aren't expanded while the body of a macro declaration is read
+    expand_macros: bool,
+    // The tokens of an expression that is being quoted in a message, and whether any come from a macro expansion
+    quoted: Option<(Vec<Token>, bool)>,
 }
 
 impl Compiler {
@@ -127,16 +245,32 @@ impl Compiler {
             infix: None,
             precedence: Precedence::PrecNone
         };
-        rules[TokenType::TokenComma as usize] = ParseRule{
+        rules[TokenType::TokenAt as usize] = ParseRule{
             prefix: None,
             infix: None,
             precedence: Precedence::PrecNone
         };
-        rules[TokenType::TokenDot as usize] = ParseRule{
+        rules[TokenType::TokenComma as usize] = ParseRule{
             prefix: None,
             infix: None,
             precedence: Precedence::PrecNone
         };
+        rules[TokenType::TokenPipe as usize] = ParseRule{
+            prefix: None,
+            infix: Some(Compiler::pipe),
+            precedence: Precedence::PrecPipe
+        };
+        // The precedence of a declared operator depends on its symbol, see infix_precedence()
+        rules[TokenType::TokenOperator as usize] = ParseRule{
+            prefix: None,
+            infix: Some(Compiler::operator),
+            precedence: Precedence::PrecNone
+        };
+        rules[TokenType::TokenDot as usize] = ParseRule{
+            prefix: None,
+            infix: Some(Compiler::dot),
+            precedence: Precedence::PrecCall
+        };
         rules[TokenType::TokenMinus as usize] = ParseRule{
             prefix: Some(Compiler::unary),
             infix: Some(Compiler::binary),
@@ -224,22 +358,22 @@ impl Compiler {
         };
         rules[TokenType::TokenGreater as usize] = ParseRule{
             prefix: None,
-            infix: Some(Compiler::binary),
+            infix: Some(Compiler::comparison),
             precedence: Precedence::PrecComparison
         };
         rules[TokenType::TokenGreaterEqual as usize] = ParseRule{
             prefix: None,
-            infix: Some(Compiler::binary),
+            infix: Some(Compiler::comparison),
             precedence: Precedence::PrecComparison
         };
         rules[TokenType::TokenLess as usize] = ParseRule{
             prefix: None,
-            infix: Some(Compiler::binary),
+            infix: Some(Compiler::comparison),
             precedence: Precedence::PrecComparison
         };
         rules[TokenType::TokenLessEqual as usize] = ParseRule{
             prefix: None,
-            infix: Some(Compiler::binary),
+            infix: Some(Compiler::comparison),
             precedence: Precedence::PrecComparison
         };
         // This rules accounts for all the "{operator}" + "=" Tokens
@@ -258,6 +392,11 @@ impl Compiler {
             infix: None,
             precedence: Precedence::PrecNone
         };
+        rules[TokenType::TokenSymbol as usize] = ParseRule{
+            prefix: Some(Compiler::symbol),
+            infix: None,
+            precedence: Precedence::PrecNone
+        };
         rules[TokenType::TokenAnd as usize] = ParseRule{
             prefix: None,
             infix: Some(Compiler::and_),
@@ -338,6 +477,21 @@ impl Compiler {
             infix: None,
             precedence: Precedence::PrecNone
         };
+        rules[TokenType::TokenAssert as usize] = ParseRule{
+            prefix: None,
+            infix: None,
+            precedence: Precedence::PrecNone
+        };
+        rules[TokenType::TokenDefer as usize] = ParseRule{
+            prefix: None,
+            infix: None,
+            precedence: Precedence::PrecNone
+        };
+        rules[TokenType::TokenRecord as usize] = ParseRule{
+            prefix: None,
+            infix: None,
+            precedence: Precedence::PrecNone
+        };
         rules[TokenType::TokenWhile as usize] = ParseRule{
             prefix: None,
             infix: None,
@@ -356,10 +510,21 @@ impl Compiler {
 
 
         Compiler { 
+            options: CompilerOptions::default(),
             parser: Parser::new(), 
             scanner: Scanner::new(),
             curr_compiler: RefCell::new(CurrCompiler::new(FunctionType::TypeScript)),
             rules: rules,
+            globals: HashMap::new(),
+            predefined_globals: HashSet::new(),
+            strict_globals: HashSet::new(),
+            operators: HashMap::new(),
+            macros: HashMap::new(),
+            expanded: VecDeque::new(),
+            lookahead: None,
+            expansions: 0,
+            expand_macros: true,
+            quoted: None,
         }
     }
 
@@ -371,10 +536,12 @@ impl Compiler {
         self.parser.had_error = false;
         self.parser.panic_mode = false;
 
-        /* the compiler implicitly claims stack slot zero for the VM’s own internal use */
-        self.curr_compiler.borrow_mut().locals.borrow_mut().push(Local { name: Token { _type: TokenType::Undefined, lexeme: "".to_string(), line: 0 }, depth: Some(0), _type: TokenType::Undefined });
-
         self.advance();
+        self.directives();
+        if self.options.strict {
+            self.strict_globals = self.scan_global_declarations();
+            self.strict_globals.extend(self.predefined_globals.iter().cloned());
+        }
 
         while !self.matching(TokenType::TokenEOF) {
             self.declaration();
@@ -389,22 +556,361 @@ impl Compiler {
         Some(function)
     }
 
+    /*
+    Directives are strings at the top of a file that turn on compiler options for that file, like
+    "use optional semicolons";
+    */
+    fn directives(&mut self) {
+        while self.check(TokenType::TokenString) {
+            match self.parser.current.lexeme.as_str() {
+                "\"use optional semicolons\"" => self.options.optional_semicolons = true,
+                "\"use strict\"" => self.options.strict = true,
+                _ => return
+            }
+            self.advance();
+            self.consume_semicolon("Expect ';' after directive.");
+        }
+    }
+
+    /*
+    In strict mode every global has to be declared somewhere in the file. Functions can use globals that are
+    declared after them, so the declarations are found by scanning the whole file before compiling it.
+    Declarations inside braces or parentheses, like function bodies and for loops, are locals.
+    Macro calls are expanded here too, since they can declare globals, and an operator declaration declares its symbol
+    */
+    fn scan_global_declarations(&self) -> HashSet<String> {
+        let mut scanner = Scanner::new();
+        scanner.source = self.scanner.source.clone();
+        let mut names: HashSet<String> = HashSet::new();
+
+        // The symbol of an operator is scanned like operator_declaration() does, so the tokens after it match the compiler's
+        let mut tokens: VecDeque<(Token, usize)> = VecDeque::new();
+        loop {
+            let token = scanner.scan_token();
+            let _type = token._type;
+            let declares_operator = _type == TokenType::TokenNumber
+                && tokens.back().is_some_and(|(keyword, _)| keyword._type == TokenType::TokenIdentifier
+                    && (keyword.lexeme == "infix" || keyword.lexeme == "infixr"));
+            tokens.push_back((token, 0));
+            if declares_operator {
+                let symbol = scanner.operator_symbol();
+                if symbol._type == TokenType::TokenOperator {
+                    scanner.add_operator(&symbol.lexeme);
+                    names.insert(symbol.lexeme.clone());
+                }
+                tokens.push_back((symbol, 0));
+            }
+            if _type == TokenType::TokenEOF { break; }
+        }
+
+        let mut macros: HashMap<String, Macro> = HashMap::new();
+        let mut depth: usize = 0;
+        let mut declaring = false;
+        while let Some((token, expansion_depth)) = tokens.pop_front() {
+            let next_type = tokens.front().map(|(next, _)| next._type);
+            match token._type {
+                TokenType::TokenEOF => break,
+                TokenType::TokenIdentifier if token.lexeme == "macro" && next_type == Some(TokenType::TokenIdentifier) => {
+                    let name = tokens.pop_front().unwrap().0.lexeme;
+                    let definition = Compiler::scan_macro(&mut tokens);
+                    macros.insert(name, definition);
+                    continue;
+                },
+                TokenType::TokenIdentifier if macros.contains_key(&token.lexeme)
+                    && next_type == Some(TokenType::TokenLeftParen) && expansion_depth < 64 => {
+                    tokens.pop_front();
+                    let args = Compiler::scan_macro_arguments(&mut tokens);
+                    let definition = &macros[&token.lexeme];
+                    let mut expansion: Vec<(Token, usize)> = Vec::new();
+                    for body_token in &definition.body {
+                        match definition.params.iter().position(|param| *param == body_token.lexeme) {
+                            Some(index) if body_token._type == TokenType::TokenIdentifier => {
+                                expansion.extend(args.get(index).cloned().unwrap_or_default());
+                            },
+                            _ => expansion.push((body_token.clone(), expansion_depth + 1))
+                        }
+                    }
+                    for expanded in expansion.into_iter().rev() {
+                        tokens.push_front(expanded);
+                    }
+                    continue;
+                },
+                TokenType::TokenLeftBrace | TokenType::TokenLeftParen => depth += 1,
+                TokenType::TokenRightBrace | TokenType::TokenRightParen => depth = depth.saturating_sub(1),
+                TokenType::TokenVar | TokenType::TokenConst | TokenType::TokenFun | TokenType::TokenRecord if depth == 0 => {
+                    declaring = true;
+                    continue;
+                },
+                TokenType::TokenIdentifier if declaring => { names.insert(token.lexeme); },
+                _ => ()
+            }
+            declaring = false;
+        }
+        names
+    }
+
+    // Reads the parameters and the body of a macro declaration for scan_global_declarations(), after its name
+    fn scan_macro(tokens: &mut VecDeque<(Token, usize)>) -> Macro {
+        let mut params: Vec<String> = Vec::new();
+        while let Some((token, _)) = tokens.pop_front() {
+            match token._type {
+                TokenType::TokenIdentifier => params.push(token.lexeme),
+                TokenType::TokenLeftParen | TokenType::TokenComma => (),
+                _ => break
+            }
+        }
+        if tokens.front().is_some_and(|(token, _)| token._type == TokenType::TokenLeftBrace) {
+            tokens.pop_front();
+        }
+
+        let mut body: Vec<Token> = Vec::new();
+        let mut nesting: usize = 0;
+        while let Some((token, _)) = tokens.front() {
+            match token._type {
+                TokenType::TokenEOF => break,
+                TokenType::TokenLeftBrace => nesting += 1,
+                TokenType::TokenRightBrace if nesting == 0 => {
+                    tokens.pop_front();
+                    break;
+                },
+                TokenType::TokenRightBrace => nesting -= 1,
+                _ => ()
+            }
+            body.push(tokens.pop_front().unwrap().0);
+        }
+        Macro { params, body }
+    }
+
+    // Reads the arguments of a macro call for scan_global_declarations(), split like expand_macro() splits them
+    fn scan_macro_arguments(tokens: &mut VecDeque<(Token, usize)>) -> Vec<Vec<(Token, usize)>> {
+        let mut args: Vec<Vec<(Token, usize)>> = Vec::new();
+        let mut arg: Vec<(Token, usize)> = Vec::new();
+        let mut nesting: usize = 0;
+        while let Some((token, token_depth)) = tokens.front().cloned() {
+            match token._type {
+                TokenType::TokenEOF => break,
+                TokenType::TokenLeftParen | TokenType::TokenLeftBrace => nesting += 1,
+                TokenType::TokenRightParen | TokenType::TokenRightBrace if nesting > 0 => nesting -= 1,
+                TokenType::TokenRightParen => {
+                    tokens.pop_front();
+                    break;
+                },
+                TokenType::TokenComma if nesting == 0 => {
+                    tokens.pop_front();
+                    args.push(std::mem::take(&mut arg));
+                    continue;
+                },
+                _ => ()
+            }
+            tokens.pop_front();
+            arg.push((token, token_depth));
+        }
+        args.push(arg);
+        args
+    }
+
     /* 
     Scanner looks at the current character and makes a token.
     Token is stored in the parser as the "current" field 
     */
     fn advance(&mut self) {
         self.parser.previous = self.parser.current.clone();
+        self.parser.previous_doc = self.parser.current_doc.take();
+        match self.parser.previous._type {
+            TokenType::TokenLeftParen => self.parser.paren_depth += 1,
+            TokenType::TokenRightParen => self.parser.paren_depth = self.parser.paren_depth.saturating_sub(1),
+            _ => ()
+        }
+        if let Some((tokens, expanded)) = &mut self.quoted {
+            tokens.push(self.parser.previous.clone());
+            *expanded |= self.parser.current_expanded;
+        }
         loop {
-            self.parser.current = self.scanner.scan_token();
+            self.parser.current_expanded = !self.expanded.is_empty();
+            let (token, depth) = self.next_token();
+            self.parser.current = token;
 
             // debugging: 
             // println!("char: {} !", self.parser.current.lexeme);
 
-            if self.parser.current._type != TokenType::TokenError { break; }
+            if self.parser.current._type == TokenType::TokenError {
+                self.error_at_current(&self.parser.current.lexeme.clone());
+            } else if self.is_macro_call() {
+                self.expand_macro(depth);
+            } else {
+                break;
+            }
+        }
+        if self.parser.current_expanded {
+            self.parser.current_doc = None;
+        }
+    }
 
-            self.error_at_current(&self.parser.current.lexeme.clone());
+    /*
+    Gets the next token along with how many macro expansions deep it is, without expanding macro calls
+    A token from the source also sets current_doc to the doc comment before it
+    */
+    fn next_token(&mut self) -> (Token, usize) {
+        if let Some(token) = self.expanded.pop_front() {
+            return token;
+        }
+        let (token, doc) = match self.lookahead.take() {
+            Some(lookahead) => lookahead,
+            None => self.scan_token()
+        };
+        self.parser.current_doc = doc;
+        (token, 0)
+    }
+
+    // Looks at the token after the current one without consuming it
+    fn peek_token(&mut self) -> Token {
+        if let Some((token, _)) = self.expanded.front() {
+            return token.clone();
+        }
+        if self.lookahead.is_none() {
+            self.lookahead = Some(self.scan_token());
+        }
+        self.lookahead.as_ref().unwrap().0.clone()
+    }
+
+    fn scan_token(&mut self) -> (Token, Option<String>) {
+        let token = self.scanner.scan_token();
+        (token, self.scanner.take_doc_comment())
+    }
+
+    fn is_macro_call(&mut self) -> bool {
+        self.expand_macros
+            && self.parser.current._type == TokenType::TokenIdentifier
+            && self.macros.contains_key(&self.parser.current.lexeme)
+            && self.peek_token()._type == TokenType::TokenLeftParen
+    }
+
+    /*
+    Replaces the macro call starting at the current token with the body of the macro, before the parser sees it.
+    The arguments are token trees, split at the commas outside of any parentheses or braces.
+    Calls in the expansion are expanded when the parser gets to them, so a macro can expand to a call of itself,
+    up to a limit. The tokens of the body keep their lines but get the number of the expansion, so the locals the
+    body declares can't be seen by the arguments, and the names it uses can't refer to the locals of the caller
+    */
+    fn expand_macro(&mut self, depth: usize) {
+        let name = self.parser.current.clone();
+        if depth >= 64 {
+            self.error_at(&name, "Too many nested macro expansions.");
+            return;
+        }
+        self.next_token(); // The '('
+
+        let mut args: Vec<Vec<(Token, usize)>> = Vec::new();
+        let mut arg: Vec<(Token, usize)> = Vec::new();
+        let mut nesting: usize = 0;
+        loop {
+            let (token, token_depth) = self.next_token();
+            match token._type {
+                TokenType::TokenEOF => {
+                    self.error_at(&token, "Expect ')' after macro arguments.");
+                    // The parser still has to see the end
+                    self.expanded.push_front((token, token_depth));
+                    return;
+                },
+                TokenType::TokenError => {
+                    self.error_at(&token, &token.lexeme.clone());
+                    continue;
+                },
+                TokenType::TokenLeftParen | TokenType::TokenLeftBrace => nesting += 1,
+                TokenType::TokenRightParen | TokenType::TokenRightBrace if nesting > 0 => nesting -= 1,
+                TokenType::TokenRightParen => {
+                    if !arg.is_empty() || !args.is_empty() {
+                        args.push(arg);
+                    }
+                    break;
+                },
+                TokenType::TokenComma if nesting == 0 => {
+                    args.push(std::mem::take(&mut arg));
+                    continue;
+                },
+                _ => ()
+            }
+            arg.push((token, token_depth));
+        }
+
+        let definition = self.macros[&name.lexeme].clone();
+        if args.len() != definition.params.len() {
+            self.error_at(&name, &format!("Expected {} arguments but got {}.", definition.params.len(), args.len()));
+            return;
+        }
+
+        self.expansions += 1;
+        let mut tokens: Vec<(Token, usize)> = Vec::new();
+        for token in definition.body {
+            let param = definition.params.iter().position(|param| *param == token.lexeme);
+            match param {
+                Some(index) if token._type == TokenType::TokenIdentifier => {
+                    // An argument of more than one token is grouped, so 'cond' in '!cond' stays a single operand
+                    // Statements have to be passed in braces, which group them already
+                    let arg = &args[index];
+                    let grouped = arg.len() > 1 && arg[0].0._type != TokenType::TokenLeftBrace;
+                    if grouped {
+                        tokens.push((Token { _type: TokenType::TokenLeftParen, lexeme: "(".to_string(), offset: None, ..arg[0].0.clone() }, depth));
+                    }
+                    tokens.extend(arg.iter().cloned());
+                    if grouped {
+                        tokens.push((Token { _type: TokenType::TokenRightParen, lexeme: ")".to_string(), offset: None, ..arg[arg.len() - 1].0.clone() }, depth));
+                    }
+                },
+                // Errors in the body are reported on the line of the call
+                _ => tokens.push((Token { expansion: self.expansions, line: name.line, ..token }, depth + 1))
+            }
+        }
+        // The expansion starts where the call did, which matters when newlines end statements
+        if let Some((first, _)) = tokens.first_mut() {
+            first.newline_before = name.newline_before;
+        }
+        for token in tokens.into_iter().rev() {
+            self.expanded.push_front(token);
+        }
+    }
+
+    // Starts keeping the tokens of an expression to quote it in a message, returns where its source text starts
+    fn begin_quote(&mut self) -> usize {
+        self.quoted = Some((Vec::new(), false));
+        self.parser.current.offset.unwrap_or(0)
+    }
+
+    // Returns the text of the expression since begin_quote()
+    fn end_quote(&mut self, text_start: usize) -> String {
+        match self.quoted.take() {
+            Some((tokens, true)) => self.expanded_text(&tokens),
+            _ => self.scanner.source_text(text_start, self.parser.current.offset.unwrap_or(text_start))
+        }
+    }
+
+    /*
+    Quotes tokens from a macro expansion, which come from both the body of the macro and the arguments of the call.
+    Tokens that follow each other in the source are quoted with the source text between them, and the others
+    are separated by a space. The parentheses the expansion puts around arguments aren't in the source, so they are left out
+    */
+    fn expanded_text(&self, tokens: &[Token]) -> String {
+        let source = self.scanner.source();
+        let mut text = String::new();
+        let mut previous_end: Option<usize> = None;
+        for token in tokens {
+            let start = match token.offset {
+                Some(start) => start,
+                None => continue
+            };
+            let end = start + token.lexeme.len();
+            match previous_end {
+                Some(previous_end) if previous_end <= start && source[previous_end..start].trim().is_empty() => {
+                    text.push_str(&source[previous_end..end]);
+                },
+                _ => {
+                    if !text.is_empty() { text.push(' '); }
+                    text.push_str(&source[start..end]);
+                }
+            }
+            previous_end = Some(end);
         }
+        text
     }
 
     /*
@@ -422,8 +928,8 @@ impl Compiler {
         self.consume(TokenType::TokenRightBrace, "Expect '}' after block.");
     }
 
-    // Create and execute a function declaration
-    fn function(&mut self, _type: FunctionType) {
+    // Create and execute a function declaration, returns the number of parameters the function has
+    fn function(&mut self, _type: FunctionType, doc: Option<String>) -> usize {
         let fun_type = _type.clone();
         let _prev_compiler: CurrCompiler = self.curr_compiler.replace(CurrCompiler::new(_type));
 
@@ -453,7 +959,23 @@ impl Compiler {
         }
 
         self.consume(TokenType::TokenRightParen, "Expect ')' after parameters.");
+        self.contracts();
         self.consume(TokenType::TokenLeftBrace, "Expect '{' after function body.");
+
+        // A string literal as the first statement of the body is the docstring of the function
+        let mut doc = doc;
+        let is_docstring = self.check(TokenType::TokenString) && {
+            let next = self.peek_token();
+            next._type == TokenType::TokenSemicolon
+                || (self.options.optional_semicolons && (next.newline_before || next._type == TokenType::TokenRightBrace))
+        };
+        if is_docstring {
+            self.advance();
+            doc = Some(self.string_literal());
+            self.consume_semicolon("Expect ';' after docstring.");
+        }
+        self.curr_compiler.borrow().function.borrow_mut().doc = doc;
+
         self.block();
 
         self.end_compiler();
@@ -466,16 +988,308 @@ impl Compiler {
         let name = self.curr_compiler.borrow().function.borrow().name.clone();
         let _function = _result.function.replace(Function::new(arity, chunk, name));
 
-        let fun_constant = self.make_constant(Value::Fun(_function));
+        let arity = _function.arity;
+        let fun_constant = self.make_constant(Value::from(_function));
         self.emit_bytes(OpCode::OpConstant as u8, fun_constant);
-        
+        arity
+    }
+
+    /*
+    Compiles the contract of a function, the 'requires' and 'ensures' clauses between its parameters and its body,
+    like 'fun sqrt(x) requires x >= 0 ensures result >= 0 { ... }'. They are only keywords in this position.
+    A precondition is checked as soon as the function is called. A postcondition is compiled once and taken out of
+    the chunk, then every return copies it back in, with the return value in a hidden local that it reads as 'result'
+    */
+    fn contracts(&mut self) {
+        let name = self.curr_compiler.borrow().function.borrow().name.clone().unwrap_or("script".to_string());
+        loop {
+            if !self.check(TokenType::TokenIdentifier) { return; }
+            let postcondition = match self.parser.current.lexeme.as_str() {
+                "requires" => false,
+                "ensures" => true,
+                _ => return
+            };
+            self.advance();
+            let clause_start = self.curr_compiler.borrow().function.borrow().chunk.code.len();
+            if postcondition {
+                self.declare_result();
+            }
+
+            let code_start = self.curr_compiler.borrow().function.borrow().chunk.code.len();
+            let text_start = self.begin_quote();
+            self.expression();
+            let text = self.end_quote(text_start);
+
+            let kind = if postcondition { "Postcondition" } else { "Precondition" };
+            let message = self.make_constant(Value::from(format!("{} of {} failed: {}", kind, name, text.trim())));
+            self.emit_byte(OpCode::OpNil as u8);
+            self.emit_bytes(OpCode::OpAssert as u8, message);
+
+            if postcondition {
+                // Only the returns can see 'result'
+                let slot = self.curr_compiler.borrow().result_slot.borrow().unwrap() as usize;
+                self.curr_compiler.borrow().locals.borrow_mut()[slot].name.lexeme = String::new();
+                let check = self.take_code(code_start);
+                if self.options.strip_contracts {
+                    // The hidden local was only declared so the clause could be compiled, so it goes away with it
+                    self.take_code(clause_start);
+                    self.curr_compiler.borrow().locals.borrow_mut().pop();
+                    *self.curr_compiler.borrow().result_slot.borrow_mut() = None;
+                } else {
+                    self.curr_compiler.borrow().ensures.borrow_mut().push(check);
+                }
+            } else if self.options.strip_contracts {
+                self.take_code(code_start);
+            }
+        }
+    }
+
+    // Makes 'result' resolve to the hidden local of the return value, declaring the local the first time
+    fn declare_result(&mut self) {
+        let slot = *self.curr_compiler.borrow().result_slot.borrow();
+        let slot = match slot {
+            Some(slot) => slot as usize,
+            None => {
+                self.emit_byte(OpCode::OpNil as u8);
+                let token = Token { _type: TokenType::TokenIdentifier, lexeme: String::new(), line: self.parser.previous.line, newline_before: false, expansion: 0, offset: None };
+                self.add_local(token, TokenType::Undefined);
+                self.mark_initialized();
+                let slot = self.curr_compiler.borrow().locals.borrow().len() - 1;
+                *self.curr_compiler.borrow().result_slot.borrow_mut() = Some(slot as u8);
+                slot
+            }
+        };
+        self.curr_compiler.borrow().locals.borrow_mut()[slot].name.lexeme = "result".to_string();
+    }
+
+    // Stores the value being returned in the hidden local and checks the postconditions against it
+    fn emit_ensures(&mut self) {
+        if self.curr_compiler.borrow().ensures.borrow().is_empty() { return; }
+
+        let slot = self.curr_compiler.borrow().result_slot.borrow().unwrap();
+        self.emit_bytes(OpCode::OpSetLocal as u8, slot);
+        let binding = self.curr_compiler.borrow();
+        let ensures = binding.ensures.borrow();
+        let mut function = binding.function.borrow_mut();
+        for check in ensures.iter() {
+            function.chunk.code.extend_from_slice(&check.code);
+            function.chunk.lines.extend_from_slice(&check.lines);
+        }
     }
 
     // Creates a function declaration
     fn fun_declaration(&mut self) {
+        // '///' comments before 'fun' document the function
+        let doc = self.parser.previous_doc.clone();
+        self.named_function(doc, 0);
+    }
+
+    /*
+    Creates a function declaration with decorators, like '@memoize fun fib(n) { ... }'
+    The decorators are evaluated from the top down and left on the stack, with the function on top of them.
+    Each decorator is then called with the value on top, so the one closest to the function is applied first
+    */
+    fn decorated_declaration(&mut self) {
+        // '///' comments go before the first decorator
+        let doc = self.parser.previous_doc.clone();
+        let mut decorators: usize = 0;
+        loop {
+            self.parse_precedence(Precedence::PrecCall);
+            decorators += 1;
+            if !self.matching(TokenType::TokenAt) { break; }
+        }
+        // Only functions can be decorated, anything else is left for the parser to recover at
+        if !self.matching(TokenType::TokenFun) {
+            self.error_at_current("Expect 'fun' after decorators.");
+            return;
+        }
+        self.named_function(doc, decorators);
+    }
+
+    // Compiles the function of a function declaration, calls its decorators on it and binds the result to the name
+    fn named_function(&mut self, doc: Option<String>, decorators: usize) {
         let global : u8 = self.parse_variable("Expect function name.", TokenType::Undefined);
         self.mark_initialized();
-        self.function(FunctionType::TypeFunction);
+        self.function(FunctionType::TypeFunction, doc);
+        for _ in 0..decorators {
+            self.emit_bytes(OpCode::OpCall as u8, 1);
+        }
+        self.define_variable(global, OpCode::OpDefineGlobal);
+    }
+
+    // 'infix' and 'infixr' are only keywords when a precedence follows them, so they can still be used as names
+    fn check_operator_declaration(&mut self) -> bool {
+        self.check(TokenType::TokenIdentifier)
+            && (self.parser.current.lexeme == "infix" || self.parser.current.lexeme == "infixr")
+            && self.peek_token()._type == TokenType::TokenNumber
+    }
+
+    /*
+    Creates an operator declaration, like 'infix 6 <+> (a, b) { ... }', or 'infixr' for a right-associative one.
+    The precedence is that of a built-in operator, from 2 for '|>' to 8 for '*'. The function is declared like a
+    const global named by the symbol, and using the operator calls it with the operands.
+    The operator is usable from its declaration on, so its own body can use it
+    */
+    fn operator_declaration(&mut self) {
+        self.advance();
+        let right_associative = self.parser.previous.lexeme == "infixr";
+        // The symbol is scanned from the source, which isn't where the tokens of an expansion come from
+        if self.parser.current_expanded {
+            self.error("Can't declare an operator in a macro.");
+            return;
+        }
+        let doc = self.parser.previous_doc.clone();
+
+        // The precedence is the current token, the scanner is right before the symbol
+        let precedence_token = self.parser.current.clone();
+        self.parser.current = self.scanner.operator_symbol();
+        // The function is still compiled after an error in the symbol, so the parser doesn't get lost in its body
+        let mut valid_symbol = self.parser.current._type != TokenType::TokenError;
+        if !valid_symbol {
+            self.error_at_current(&self.parser.current.lexeme.clone());
+        }
+        self.advance();
+        let symbol = self.parser.previous.clone();
+
+        let precedence = match parse_number(&precedence_token.lexeme) {
+            Ok(level) if level.fract() == 0.0 && (2.0..=8.0).contains(&level) => Precedence::from(level as usize),
+            _ => {
+                self.error_at(&precedence_token, "Operator precedence must be a whole number from 2 to 8.");
+                Precedence::PrecTerm
+            }
+        };
+        if *self.curr_compiler.borrow().scope_depth.borrow() > 0 {
+            self.error_at(&symbol, "Operators can only be declared at the top level.");
+        }
+        if valid_symbol && Scanner::is_built_in_operator(&symbol.lexeme) {
+            self.error_at(&symbol, "Can't redeclare a built-in operator.");
+            valid_symbol = false;
+        }
+        if let Some(code) = valid_symbol.then(|| Compiler::built_in_use(&symbol.lexeme)).flatten() {
+            self.error_at(&symbol, &format!("Can't declare an operator that is already valid code, like '{}'.", code));
+            valid_symbol = false;
+        }
+        if self.operators.contains_key(&symbol.lexeme) {
+            self.error_at(&symbol, "Already an operator with this symbol.");
+        }
+        if valid_symbol {
+            self.scanner.add_operator(&symbol.lexeme);
+            self.operators.insert(symbol.lexeme.clone(), DeclaredOperator { precedence, right_associative });
+        }
+
+        self.declare_global(&symbol, true);
+        let global = self.identifier_constant(symbol.clone());
+        let arity = self.function(FunctionType::TypeFunction, doc);
+        if arity != 2 {
+            self.error_at(&symbol, "An operator must have two parameters.");
+        }
+        self.emit_bytes(OpCode::OpDefineConstGlobal as u8, global);
+    }
+
+    /*
+    Returns code that already has the symbol in it without declaring it, like 'a - -b' for '--', which is an operator
+    followed by prefix operators. Declaring the symbol would change what that code means, since the scanner
+    would scan it as one token
+    */
+    fn built_in_use(symbol: &str) -> Option<String> {
+        let tokens = Scanner::built_in_tokens(symbol);
+        let prefix_operators = tokens.get(1..)?;
+        if prefix_operators.is_empty()
+            || !prefix_operators.iter().all(|token| matches!(token._type, TokenType::TokenMinus | TokenType::TokenBang))
+            || tokens.iter().any(|token| token._type == TokenType::TokenError) {
+            return None;
+        }
+        let prefix: String = prefix_operators.iter().map(|token| token.lexeme.as_str()).collect();
+        Some(format!("a {} {}b", tokens[0].lexeme, prefix))
+    }
+
+    // 'macro' is only a keyword when a name follows it
+    fn check_macro_declaration(&mut self) -> bool {
+        self.check(TokenType::TokenIdentifier)
+            && self.parser.current.lexeme == "macro"
+            && self.peek_token()._type == TokenType::TokenIdentifier
+    }
+
+    /*
+    Creates a macro declaration, like 'macro unless(cond, body) { if (!cond) body }'
+    No code is emitted for it, the tokens of the body are kept and used in place of every call of the macro after it
+    */
+    fn macro_declaration(&mut self) {
+        // The body is only expanded where the macro is used, so the calls in it are kept as they are
+        self.expand_macros = false;
+        self.advance();
+        let keyword = self.parser.previous.clone();
+        self.consume(TokenType::TokenIdentifier, "Expect macro name.");
+        let name = self.parser.previous.lexeme.clone();
+
+        self.consume(TokenType::TokenLeftParen, "Expect '(' after macro name.");
+        let mut params: Vec<String> = Vec::new();
+        if !self.check(TokenType::TokenRightParen) {
+            loop {
+                self.consume(TokenType::TokenIdentifier, "Expect parameter name.");
+                let param = self.parser.previous.lexeme.clone();
+                if params.contains(&param) {
+                    self.error("Already a parameter with this name in this macro.");
+                }
+                params.push(param);
+                if !self.matching(TokenType::TokenComma) { break; }
+            }
+        }
+        self.consume(TokenType::TokenRightParen, "Expect ')' after parameters.");
+        self.consume(TokenType::TokenLeftBrace, "Expect '{' before macro body.");
+
+        let mut body: Vec<Token> = Vec::new();
+        let mut nesting: usize = 0;
+        while !self.check(TokenType::TokenEOF) {
+            match self.parser.current._type {
+                TokenType::TokenLeftBrace => nesting += 1,
+                TokenType::TokenRightBrace if nesting == 0 => break,
+                TokenType::TokenRightBrace => nesting -= 1,
+                _ => ()
+            }
+            self.advance();
+            body.push(self.parser.previous.clone());
+        }
+        // The token after the declaration can already be a call of the macro
+        self.macros.insert(name, Macro { params, body });
+        self.expand_macros = true;
+        self.consume(TokenType::TokenRightBrace, "Expect '}' after macro body.");
+
+        if *self.curr_compiler.borrow().scope_depth.borrow() > 0 && !self.parser.panic_mode {
+            self.error_at(&keyword, "Macros can only be declared at the top level.");
+            // The whole declaration was read, so the parser has nothing to recover from
+            self.parser.panic_mode = false;
+        }
+    }
+
+    // Creates a record declaration, like 'record Point(x, y);'
+    // The record type is a constant, defined like any other variable, and calling it creates a record
+    fn record_declaration(&mut self) {
+        let global : u8 = self.parse_variable("Expect record name.", TokenType::Undefined);
+        let name = self.parser.previous.lexeme.clone();
+        self.mark_initialized();
+
+        self.consume(TokenType::TokenLeftParen, "Expect '(' after record name.");
+        let mut fields: Vec<String> = Vec::new();
+        if !self.check(TokenType::TokenRightParen) {
+            loop {
+                self.consume(TokenType::TokenIdentifier, "Expect field name.");
+                let field = self.parser.previous.lexeme.clone();
+                if fields.contains(&field) {
+                    self.error("Already a field with this name in this record.");
+                }
+                fields.push(field);
+                if fields.len() > 255 {
+                    self.error_at_current("Can't have more than 255 fields.");
+                }
+                if !self.matching(TokenType::TokenComma) { break; }
+            }
+        }
+        self.consume(TokenType::TokenRightParen, "Expect ')' after record fields.");
+        self.consume_semicolon("Expect ';' after record declaration.");
+
+        let record_type = self.make_constant(Value::RecordType(Arc::new(RecordType { name, fields })));
+        self.emit_bytes(OpCode::OpConstant as u8, record_type);
         self.define_variable(global, OpCode::OpDefineGlobal);
     }
 
@@ -492,7 +1306,7 @@ impl Compiler {
             }
             
         }
-        self.consume(TokenType::TokenSemicolon, "Expect ';' after variable declaration.");
+        self.consume_semicolon("Expect ';' after variable declaration.");
         
         if token_type == TokenType::TokenVar{
             self.define_variable(global, OpCode::OpDefineGlobal);
@@ -504,7 +1318,7 @@ impl Compiler {
     // Checks that an expression is followed by a semicolon
     fn expression_statement(&mut self) {
         self.expression();
-        self.consume(TokenType::TokenSemicolon, "Expect ';' after value.");
+        self.consume_semicolon("Expect ';' after value.");
         self.emit_byte(OpCode::OpPop as u8);
     }
 
@@ -584,10 +1398,73 @@ impl Compiler {
         self.patch_jump(else_jump);
     }
 
+    /*
+    Creates assert statement declaration
+    The condition is quoted from the source so the runtime error can show what failed
+    */
+    fn assert_statement(&mut self) {
+        let line = self.parser.previous.line;
+        let code_start = self.curr_compiler.borrow().function.borrow().chunk.code.len();
+        let text_start = self.begin_quote();
+        self.expression();
+        let text = self.end_quote(text_start);
+
+        // The message is only evaluated when the assertion fails
+        let fail_jump = self.emit_jump(OpCode::OpJumpIfFalse as u8);
+        self.emit_byte(OpCode::OpPop as u8);
+        let end_jump = self.emit_jump(OpCode::OpJump as u8);
+        self.patch_jump(fail_jump);
+
+        if self.matching(TokenType::TokenComma) {
+            self.expression();
+        } else {
+            self.emit_byte(OpCode::OpNil as u8);
+        }
+        self.consume_semicolon("Expect ';' after assertion.");
+
+        // Stripped assertions are still parsed so they get checked for errors
+        if self.options.strip_asserts {
+            self.take_code(code_start);
+            return;
+        }
+
+        let message = self.make_constant(Value::from(format!("Assertion failed on line {}: {}", line, text.trim())));
+        self.emit_bytes(OpCode::OpAssert as u8, message);
+        self.patch_jump(end_jump);
+    }
+
+    /*
+    Creates defer statement declaration
+    The expression is compiled once and taken out of the chunk, then copied back in before every return inside
+    its block and at the end of the block. A defer in the function body runs when the function returns,
+    one in a nested block when the block is left
+    */
+    fn defer_statement(&mut self) {
+        if self.curr_compiler.borrow().fun_type == FunctionType::TypeScript {
+            self.error("Can't defer outside of a function.");
+        }
+
+        let index = *self.curr_compiler.borrow().defer_count.borrow();
+        if index > u8::MAX as usize {
+            self.error("Too many defer statements in one function.");
+        }
+        *self.curr_compiler.borrow().defer_count.borrow_mut() += 1;
+        // The statement only records that it ran, the returns after it check that before running its code
+        self.emit_bytes(OpCode::OpDefer as u8, index as u8);
+
+        let code_start = self.curr_compiler.borrow().function.borrow().chunk.code.len();
+        self.expression();
+        self.consume_semicolon("Expect ';' after deferred expression.");
+
+        let code = self.take_code(code_start);
+        let scope_depth = *self.curr_compiler.borrow().scope_depth.borrow();
+        self.curr_compiler.borrow().defers.borrow_mut().push(Deferred { scope_depth, index: index as u8, code });
+    }
+
     // Creates print statement declaration
     fn print_statement(&mut self) {
         self.expression();
-        self.consume(TokenType::TokenSemicolon, "Expect ';' after value.");
+        self.consume_semicolon("Expect ';' after value.");
         self.emit_byte(OpCode::OpPrint as u8);
     }
 
@@ -598,15 +1475,36 @@ impl Compiler {
         }
 
         // Return Nil implicitly if no expression is given with an OpReturn instruction
-        if self.matching(TokenType::TokenSemicolon){
+        if self.matching(TokenType::TokenSemicolon) || self.at_statement_end() {
             self.emit_return();
         } else { // Otherwise, compile the return value expression and return it with an OpInstruction
             self.expression();
-            self.consume(TokenType::TokenSemicolon, "Expect ';' after return value.");
+            self.consume_semicolon("Expect ';' after return value.");
+            self.tail_call();
+            // Deferred cleanup runs first, so the postconditions see the state the caller gets
+            self.emit_deferred(0);
+            self.emit_ensures();
             self.emit_byte(OpCode::OpReturn as u8);
         }
     }
 
+    /*
+    If the return value ends with a call, turns that call into a tail call, which reuses the frame of the
+    current function instead of pushing a new one.
+    The OpReturn after it is still needed, for natives and for short circuits like 'return a or f();'
+    Functions with defer statements or postconditions have to run them after the call, so they keep the normal call
+    */
+    fn tail_call(&mut self) {
+        let binding = self.curr_compiler.borrow();
+        if !binding.defers.borrow().is_empty() || !binding.ensures.borrow().is_empty() { return; }
+
+        let mut function = binding.function.borrow_mut();
+        let code_len = function.chunk.code.len();
+        if code_len >= 2 && *binding.last_call.borrow() == Some(code_len - 2) {
+            function.chunk.code[code_len - 2] = OpCode::OpTailCall as u8;
+        }
+    }
+
     // Creates while loop statement declaration
     fn while_statement(&mut self){
         // Get position where loop starts
@@ -633,31 +1531,44 @@ impl Compiler {
 
         while self.parser.current._type != TokenType::TokenEOF {
             if self.parser.previous._type == TokenType::TokenSemicolon { return; }
+            if self.options.optional_semicolons && self.parser.current.newline_before { return; }
             match self.parser.current._type {
-                TokenType::TokenClass => (),
-                TokenType::TokenFun => (),
-                TokenType::TokenVar => (),
-                TokenType::TokenConst => (),
-                TokenType::TokenFor => (),
-                TokenType::TokenIf => (),
-                TokenType::TokenWhile => (),
-                TokenType::TokenPrint => (),
+                TokenType::TokenClass => return,
+                TokenType::TokenFun => return,
+                TokenType::TokenAt => return,
+                TokenType::TokenVar => return,
+                TokenType::TokenConst => return,
+                TokenType::TokenFor => return,
+                TokenType::TokenIf => return,
+                TokenType::TokenWhile => return,
+                TokenType::TokenPrint => return,
+                TokenType::TokenAssert => return,
+                TokenType::TokenDefer => return,
+                TokenType::TokenRecord => return,
                 TokenType::TokenReturn => return,
                 _ => (),
             }
-        }
 
-        self.advance();
+            self.advance();
+        }
     }
 
     /* Determine what kind of declaration it is */
     fn declaration(&mut self) {
         if self.matching(TokenType::TokenFun) {
             self.fun_declaration();
+        } else if self.matching(TokenType::TokenAt) {
+            self.decorated_declaration();
         } else if self.matching(TokenType::TokenVar) {
             self.var_declaration(TokenType::TokenVar);
         } else if self.matching(TokenType::TokenConst) {
             self.var_declaration(TokenType::TokenConst);
+        } else if self.matching(TokenType::TokenRecord) {
+            self.record_declaration();
+        } else if self.check_operator_declaration() {
+            self.operator_declaration();
+        } else if self.check_macro_declaration() {
+            self.macro_declaration();
         } else {
             self.statement();
         }
@@ -676,6 +1587,10 @@ impl Compiler {
             self.if_statement();
         } else if self.matching(TokenType::TokenReturn){
             self.return_statement();
+        } else if self.matching(TokenType::TokenAssert){
+            self.assert_statement();
+        } else if self.matching(TokenType::TokenDefer){
+            self.defer_statement();
         } else if self.matching(TokenType::TokenWhile) {
             self.while_statement();
         } else if self.matching(TokenType::TokenLeftBrace){
@@ -696,6 +1611,28 @@ impl Compiler {
         self.error_at_current(message);
     }
 
+    // Consumes the ';' at the end of a statement, which can be left out in optional semicolon mode
+    fn consume_semicolon(&mut self, message: &str) {
+        if !self.check(TokenType::TokenSemicolon) && self.at_statement_end() {
+            return;
+        }
+        self.consume(TokenType::TokenSemicolon, message);
+    }
+
+    // In optional semicolon mode, a statement also ends at a newline, before a '}' or at the end of the file
+    fn at_statement_end(&mut self) -> bool {
+        self.options.optional_semicolons && (self.parser.current.newline_before
+            || self.check(TokenType::TokenRightBrace) || self.check(TokenType::TokenEOF))
+    }
+
+    // In optional semicolon mode, an expression ends at a newline unless it is inside parentheses
+    // A line ending in a binary operator still continues, since the operator needs its right operand
+    // A line starting with '|>' continues the pipeline on the line before, since no statement starts with it
+    fn newline_ends_expression(&self) -> bool {
+        self.options.optional_semicolons && self.parser.current.newline_before && self.parser.paren_depth == 0
+            && self.parser.current._type != TokenType::TokenPipe
+    }
+
     fn check(&mut self, token_type: TokenType) -> bool {
         self.parser.current._type == token_type
     }
@@ -721,7 +1658,12 @@ impl Compiler {
 
     // Adds a byte into the chunk of the current compiler
     fn emit_byte(&mut self, byte: u8) {
-        self.curr_compiler.borrow_mut().function.borrow_mut().chunk.write_chunk(byte, self.parser.previous.line);
+        self.emit_byte_on_line(byte, self.parser.previous.line);
+    }
+
+    // Adds a byte into the chunk of the current compiler, for code that belongs to an earlier line
+    fn emit_byte_on_line(&mut self, byte: u8, line: usize) {
+        self.curr_compiler.borrow_mut().function.borrow_mut().chunk.write_chunk(byte, line);
     }
 
     // Adds two bytes into the chunk of the current compiler
@@ -757,6 +1699,44 @@ impl Compiler {
         return self.curr_compiler.borrow().function.borrow().chunk.lines.len() - 2; 
     }
     
+    // Removes all the code emitted after the given offset from the current chunk and returns it
+    // The last call may have been in that code, so it is forgotten and can't be patched into a tail call
+    fn take_code(&mut self, offset: usize) -> CodeSegment {
+        let binding = self.curr_compiler.borrow();
+        *binding.last_call.borrow_mut() = None;
+        let mut function = binding.function.borrow_mut();
+        CodeSegment {
+            code: function.chunk.code.split_off(offset),
+            lines: function.chunk.lines.split_off(offset),
+        }
+    }
+
+    // Copies the code of the defer statements in scopes at least as deep as the given one, the most recent one first
+    // The code of each one is skipped when its defer statement didn't run, like one in an 'if' that wasn't taken
+    fn emit_deferred(&mut self, scope_depth: usize) {
+        let defers: Vec<Deferred> = self.curr_compiler.borrow().defers.borrow().iter().rev()
+            .filter(|deferred| deferred.scope_depth >= scope_depth)
+            .cloned()
+            .collect();
+        for deferred in defers {
+            let line = deferred.code.lines.first().copied().unwrap_or(self.parser.previous.line);
+            self.emit_byte_on_line(OpCode::OpDeferred as u8, line);
+            self.emit_byte_on_line(deferred.index, line);
+            let skip = self.emit_jump(OpCode::OpJumpIfFalse as u8);
+            self.emit_byte_on_line(OpCode::OpPop as u8, line);
+            {
+                let binding = self.curr_compiler.borrow();
+                let mut function = binding.function.borrow_mut();
+                function.chunk.code.extend_from_slice(&deferred.code.code);
+                function.chunk.lines.extend_from_slice(&deferred.code.lines);
+            }
+            self.patch_jump(skip);
+            // Pops the value of the expression, or the false when it was skipped
+            self.emit_byte_on_line(OpCode::OpPop as u8, line);
+        }
+        *self.curr_compiler.borrow().last_call.borrow_mut() = None;
+    }
+
     // Activate debug_print_code feature to print a chunk log for debugging
     #[allow(unused)]
     fn debug_print_code(&mut self) {
@@ -795,11 +1775,18 @@ impl Compiler {
     Since scope is finished, decrease scope depth by 1
     */
     fn end_scope(&mut self){
+        // The defers of the block run before its locals are popped, since they can use them
+        let block_depth = *self.curr_compiler.borrow().scope_depth.borrow();
+        if self.curr_compiler.borrow().defers.borrow().iter().any(|deferred| deferred.scope_depth >= block_depth) {
+            self.emit_deferred(block_depth);
+            self.curr_compiler.borrow().defers.borrow_mut().retain(|deferred| deferred.scope_depth < block_depth);
+        }
+
         *self.curr_compiler.borrow_mut().scope_depth.borrow_mut() -= 1;
         let scope_depth = *self.curr_compiler.borrow_mut().scope_depth.borrow();
         let depth = self.curr_compiler.borrow_mut().locals.borrow().len();
         // Pop any local variables declared at the scope depth we just left
-        while depth > 0 && self.curr_compiler.borrow_mut().locals.borrow().last().unwrap_or(&Local { name: Token { _type: TokenType::Undefined, lexeme: "".to_string(), line: 0 }, depth: None, _type: TokenType::Undefined }).depth.unwrap_or(0) > scope_depth{
+        while depth > 0 && self.curr_compiler.borrow_mut().locals.borrow().last().unwrap_or(&Local { name: Token { _type: TokenType::Undefined, lexeme: "".to_string(), line: 0, newline_before: false, expansion: 0, offset: None }, depth: None, _type: TokenType::Undefined }).depth.unwrap_or(0) > scope_depth{
             self.emit_byte(OpCode::OpPop as u8);
             self.curr_compiler.borrow_mut().locals.borrow_mut().pop();
         }
@@ -833,7 +1820,7 @@ impl Compiler {
         /*
         Keep checking until token has too low precedence. 
         */
-        while precedence <= self.get_rule(self.parser.current._type).precedence {
+        while precedence <= self.infix_precedence() && !self.newline_ends_expression() {
             self.advance();
             let infix_rule = self.get_rule(self.parser.previous._type).infix.unwrap();
             // consume the operator and hand off control to the infix parser we found
@@ -869,8 +1856,9 @@ impl Compiler {
     }
 
     // Checks if two tokens are equal
+    // A name from a macro expansion is different from the same name written anywhere else
     fn identifier_equal(&mut self, a: &Token, b: &Token) -> bool {
-        if a.lexeme.len() != b.lexeme.len() {
+        if a.lexeme.len() != b.lexeme.len() || a.expansion != b.expansion {
             return false;
         }
         return a.lexeme == b.lexeme;
@@ -931,12 +1919,24 @@ impl Compiler {
         self.consume(TokenType::TokenIdentifier, error_message);
         self.declare_variable(_type);
         if *self.curr_compiler.borrow().scope_depth.borrow() == 0 {
-            return self.identifier_constant(self.parser.previous.clone())
+            let name = self.parser.previous.clone();
+            self.declare_global(&name, _type == TokenType::TokenConst);
+            return self.identifier_constant(name)
         } else {
             0
         } 
     }
 
+    /*
+    Records the declaration of a global, so assignments to a const global can be rejected while compiling
+    */
+    fn declare_global(&mut self, name: &Token, constant: bool) {
+        if let Some(GlobalInfo { constant: true }) = self.globals.get(&name.lexeme) {
+            self.error_at(name, "Const variable with this name is already defined.");
+        }
+        self.globals.insert(name.lexeme.clone(), GlobalInfo { constant });
+    }
+
     // Marks the depth of the last local in the vector
     fn mark_initialized(&mut self) {
         if *self.curr_compiler.borrow().scope_depth.borrow() == 0 { return; }
@@ -998,6 +1998,8 @@ impl Compiler {
     */
     fn emit_return(&mut self) {
         self.emit_byte(OpCode::OpNil as u8);
+        self.emit_deferred(0);
+        self.emit_ensures();
         self.emit_byte(OpCode::OpReturn as u8);
     }
 
@@ -1045,11 +2047,19 @@ impl Compiler {
 
     /**
      * We assume the token for the number literal has already been consumed and is stored in previous.
-     * We take that lexeme and use the Rust parse() method to convert it to an Option<f64>, and unwrap() to make it an f64.
+     * We take that lexeme and convert it to an f64, reporting a compile error if the literal is malformed.
      */
     fn number(&mut self, _can_assign: bool) {
-        let _number:f64 = self.parser.previous.lexeme.parse().unwrap();
-        self.emit_constant(Value::Number(_number));
+        match parse_number(&self.parser.previous.lexeme) {
+            Ok(_number) => self.emit_constant(Value::Number(_number)),
+            Err(message) => self.error(message)
+        }
+    }
+
+    // Symbol literal parser function, the name after the ':' is interned when the code is compiled
+    fn symbol(&mut self, _can_assign: bool) {
+        let name = self.parser.previous.lexeme[1..].to_string();
+        self.emit_constant(Value::Symbol(Symbol::intern(&name)));
     }
 
     fn or_(&mut self, _can_assign: bool){
@@ -1064,47 +2074,66 @@ impl Compiler {
     }
 
     /**
-     * Takes the string’s characters directly from the lexeme
+     * Takes the string’s characters directly from the lexeme and replaces the escape sequences
      * Uses that string to wrap it in a Value, and stuffs it into the constant table.
      */
     fn string(&mut self, _can_assign: bool) {
-        let end_index = self.parser.previous.lexeme.len() - 1;
-        let _string:String = self.parser.previous.lexeme.substring(1, end_index);
+        let _string: String = self.string_literal();
         self.emit_constant(Value::from(_string));
     }
 
+    // Returns the value of the string literal in the previous token
+    fn string_literal(&mut self) -> String {
+        // The scanner already rejected invalid escape sequences
+        match string_value(&self.parser.previous.lexeme) {
+            Ok(_string) => _string,
+            Err((_, message)) => {
+                self.error(message);
+                String::new()
+            }
+        }
+    }
+
     /**
      * Checks whether the variable should be local or global
      * Adds the name of the variable to the table
      */
     fn named_variable(&mut self, name: Token, _can_assign: bool) {
         let (get_op, set_op): (u8, u8);
+        let constant: bool;
         let mut arg = self.resolve_local(&name);
 
         if arg != None{
             get_op = OpCode::OpGetLocal as u8;
+            set_op = OpCode::OpSetLocal as u8;
             
             let idx:usize = arg.unwrap() as usize;
             let local = self.curr_compiler.borrow_mut().locals.borrow()[idx].clone();
-            if local._type == TokenType::TokenConst{
-                set_op = OpCode::OpSetConstLocal as u8;
-            }
-            else{
-                set_op = OpCode::OpSetLocal as u8;
-            }
+            constant = local._type == TokenType::TokenConst;
         } 
         else {
-            arg = Some(self.identifier_constant(name) as usize);
+            if self.options.strict && !self.strict_globals.contains(&name.lexeme) {
+                self.error_at(&name, "Undefined variable.");
+            }
+            // A const global declared later in the source is still caught by the VM
+            constant = matches!(self.globals.get(&name.lexeme), Some(GlobalInfo { constant: true }));
+            arg = Some(self.identifier_constant(name.clone()) as usize);
             get_op = OpCode::OpGetGlobal as u8;
             set_op = OpCode::OpSetGlobal as u8;  
         }
 
         if _can_assign && self.matching(TokenType::TokenEqual) {
+            if constant {
+                self.error_at(&name, "Can't assign to const variable.");
+            }
             self.expression();
             self.emit_bytes(set_op, arg.unwrap() as u8);
         } else if _can_assign && self.matching_list(vec![TokenType::TokenPlusEqual, TokenType::TokenMinusEqual, 
                 TokenType::TokenSlashEqual, TokenType::TokenStarEqual, TokenType::TokenCaratEqual, TokenType::TokenPercentEqual]) {
             
+            if constant {
+                self.error_at(&name, "Can't assign to const variable.");
+            }
             let equals_operator_type = self.parser.previous._type.clone();  // gets the operator attached to equal
             self.emit_bytes(get_op, arg.unwrap() as u8);    // adds the left hand side variable value to stack
             self.expression();  // solves the right hand side operations
@@ -1156,10 +2185,6 @@ impl Compiler {
         match operator_type {
             TokenType::TokenBangEqual => self.emit_bytes(OpCode::OpEqual as u8, OpCode::OpNot as u8),
             TokenType::TokenEqualEqual => self.emit_byte(OpCode::OpEqual as u8),
-            TokenType::TokenGreater => self.emit_byte(OpCode::OpGreater as u8),
-            TokenType::TokenGreaterEqual => self.emit_bytes(OpCode::OpLess as u8, OpCode::OpNot as u8),
-            TokenType::TokenLess => self.emit_byte(OpCode::OpLess as u8),
-            TokenType::TokenLessEqual => self.emit_bytes(OpCode::OpGreater as u8, OpCode::OpNot as u8),
             TokenType::TokenPlus => self.emit_byte(OpCode::OpAdd as u8),
             TokenType::TokenMinus => self.emit_byte(OpCode::OpSubtract as u8),
             TokenType::TokenStar => self.emit_byte(OpCode::OpMultiply as u8),
@@ -1170,12 +2195,162 @@ impl Compiler {
         }
     }
 
+    /*
+    Comparison parser function, which also handles chains like '0 <= x < 10' meaning '0 <= x and x < 10'
+    When another comparison follows, the middle operand is copied under the result so the next comparison
+    can use it without evaluating it again. A false result jumps to the end, where the copy is dropped
+    */
+    fn comparison(&mut self, _can_assign: bool) {
+        let mut false_jumps: Vec<usize> = Vec::new();
+        loop {
+            let operator_type = self.parser.previous._type;
+            self.parse_precedence(Precedence::PrecTerm);
+
+            let chained = Compiler::is_comparison(self.parser.current._type) && !self.newline_ends_expression();
+            if chained {
+                // [left, middle] becomes [middle, left, middle]
+                self.emit_bytes(OpCode::OpSwap as u8, OpCode::OpOver as u8);
+            }
+            match operator_type {
+                TokenType::TokenGreater => self.emit_byte(OpCode::OpGreater as u8),
+                TokenType::TokenGreaterEqual => self.emit_bytes(OpCode::OpLess as u8, OpCode::OpNot as u8),
+                TokenType::TokenLess => self.emit_byte(OpCode::OpLess as u8),
+                TokenType::TokenLessEqual => self.emit_bytes(OpCode::OpGreater as u8, OpCode::OpNot as u8),
+                _ => return // Unreachable
+            }
+            if !chained {
+                break;
+            }
+
+            false_jumps.push(self.emit_jump(OpCode::OpJumpIfFalse as u8));
+            self.emit_byte(OpCode::OpPop as u8);
+            self.advance();
+        }
+
+        if !false_jumps.is_empty() {
+            let end_jump = self.emit_jump(OpCode::OpJump as u8);
+            for false_jump in false_jumps {
+                self.patch_jump(false_jump);
+            }
+            // [middle, false] becomes [false]
+            self.emit_bytes(OpCode::OpSwap as u8, OpCode::OpPop as u8);
+            self.patch_jump(end_jump);
+        }
+    }
+
+    fn is_comparison(token_type: TokenType) -> bool {
+        matches!(token_type, TokenType::TokenGreater | TokenType::TokenGreaterEqual
+            | TokenType::TokenLess | TokenType::TokenLessEqual)
+    }
+
     // Call parser function
     fn call(&mut self, _can_assign: bool){
         let arg_count: u8 = self.argument_list();
+        let call_offset = self.curr_compiler.borrow().function.borrow().chunk.code.len();
+        *self.curr_compiler.borrow().last_call.borrow_mut() = Some(call_offset);
         self.emit_bytes(OpCode::OpCall as u8, arg_count);
     }
 
+    /*
+    Pipe parser function, 'x |> f(y)' calls f(x, y) and 'x |> f' calls f(x)
+    The value on the left is already on the stack, so the callee goes on top of it and the two are swapped
+    before the rest of the arguments. The call belongs to the line of the '|>', so runtime errors in long
+    pipelines point at the right step
+    */
+    fn pipe(&mut self, _can_assign: bool){
+        let line = self.parser.previous.line;
+        // The callee can be a chain of properties and calls, like 'obj.method' or 'make()', and the last call
+        // of the chain, if there is one, is the one being rewritten
+        self.parse_precedence(Precedence::PrecPrimary);
+        let mut arguments: Option<(CodeSegment, u8)> = None;
+        loop {
+            if self.matching(TokenType::TokenDot) {
+                self.consume(TokenType::TokenIdentifier, "Expect property name after '.'.");
+                let name: u8 = self.identifier_constant(self.parser.previous.clone());
+                self.emit_bytes(OpCode::OpGetProperty as u8, name);
+            } else if self.matching(TokenType::TokenLeftParen) {
+                let args_start = self.curr_compiler.borrow().function.borrow().chunk.code.len();
+                let count = self.argument_list();
+                if self.check(TokenType::TokenDot) || self.check(TokenType::TokenLeftParen) {
+                    self.emit_bytes(OpCode::OpCall as u8, count);
+                } else {
+                    arguments = Some((self.take_code(args_start), count));
+                    break;
+                }
+            } else {
+                break;
+            }
+        }
+        self.emit_byte_on_line(OpCode::OpSwap as u8, line);
+
+        // The value on the left is the first argument
+        let mut arg_count: usize = 1;
+        if let Some((code, count)) = arguments {
+            let binding = self.curr_compiler.borrow();
+            let mut function = binding.function.borrow_mut();
+            function.chunk.code.extend_from_slice(&code.code);
+            function.chunk.lines.extend_from_slice(&code.lines);
+            arg_count += count as usize;
+        }
+        if arg_count > 255 {
+            self.error("Can't have more than 255 arguments.");
+        }
+        let call_offset = self.curr_compiler.borrow().function.borrow().chunk.code.len();
+        *self.curr_compiler.borrow().last_call.borrow_mut() = Some(call_offset);
+        self.emit_byte_on_line(OpCode::OpCall as u8, line);
+        self.emit_byte_on_line(arg_count as u8, line);
+    }
+
+    /*
+    Declared operator parser function, 'a <+> b' calls the function of '<+>' with a and b
+    Like a pipe, the function goes on top of the left operand and the two are swapped
+    */
+    fn operator(&mut self, _can_assign: bool){
+        let symbol = self.parser.previous.clone();
+        let operator = self.operators[&symbol.lexeme];
+        let function = self.identifier_constant(symbol.clone());
+        self.emit_bytes(OpCode::OpGetGlobal as u8, function);
+        self.emit_byte(OpCode::OpSwap as u8);
+
+        // A right-associative operator takes the rest of a chain like 'a <+> b <+> c' as its right operand
+        if operator.right_associative {
+            self.parse_precedence(operator.precedence);
+        } else {
+            self.parse_precedence(operator.precedence.next());
+        }
+        let call_offset = self.curr_compiler.borrow().function.borrow().chunk.code.len();
+        *self.curr_compiler.borrow().last_call.borrow_mut() = Some(call_offset);
+        self.emit_byte_on_line(OpCode::OpCall as u8, symbol.line);
+        self.emit_byte_on_line(2, symbol.line);
+    }
+
+    // The precedence of the current token as an infix operator
+    fn infix_precedence(&self) -> Precedence {
+        let token = &self.parser.current;
+        if token._type == TokenType::TokenOperator {
+            return self.operators[&token.lexeme].precedence;
+        }
+        self.rules[token._type as usize].precedence
+    }
+
+    // Dot parser function, for method calls on built-in values like "abc".len() and record fields like p.x
+    fn dot(&mut self, can_assign: bool){
+        self.consume(TokenType::TokenIdentifier, "Expect property name after '.'.");
+        let name: u8 = self.identifier_constant(self.parser.previous.clone());
+        if self.matching(TokenType::TokenLeftParen) {
+            let arg_count: u8 = self.argument_list();
+            self.emit_bytes(OpCode::OpInvoke as u8, name);
+            self.emit_byte(arg_count);
+        } else if can_assign && self.matching_list(vec![TokenType::TokenEqual, TokenType::TokenPlusEqual,
+                TokenType::TokenMinusEqual, TokenType::TokenStarEqual, TokenType::TokenSlashEqual,
+                TokenType::TokenCaratEqual, TokenType::TokenPercentEqual]) {
+            // Records are immutable
+            self.error("Can't assign to a field of a record.");
+        } else {
+            self.emit_bytes(OpCode::OpGetProperty as u8, name);
+        }
+    }
+
     // When the parser encounters false, nil, or true, in prefix position, it calls this literal parser function 
     fn literal(&mut self, _can_assign: bool) {
         match self.parser.previous._type {
@@ -1221,33 +2396,39 @@ impl Compiler {
 
 }
 
-trait StringUtils {
-    // Trait and implementation for a method for String that returns
-    // a substring, which begins at the specified begin_index and extends
-    // to the character at index end_index - 1
-    fn substring(&self, begin_index: usize, end_index: usize) -> Self;
-    // Gets the character in a position
-    fn char_at(&mut self, index_pos: usize) -> char;
-}
-
-
-impl StringUtils for String {
-    fn substring(&self, begin_index: usize, end_index: usize) -> Self {
-        if begin_index + (end_index - begin_index) > self.len() {
-            panic!("substring(): index out of bounds");
+/*
+Converts the lexeme of a number literal into its value.
+Handles the 0x, 0b and 0o prefixes, '_' separators between digits and exponents
+*/
+fn parse_number(lexeme: &str) -> Result<Number, &'static str> {
+    let (radix, digits): (u32, &str) = match lexeme.get(0..2) {
+        Some("0x") | Some("0X") => (16, &lexeme[2..]),
+        Some("0b") | Some("0B") => (2, &lexeme[2..]),
+        Some("0o") | Some("0O") => (8, &lexeme[2..]),
+        _ => (10, lexeme)
+    };
+
+    // Every separator needs a digit on both sides
+    let chars: Vec<char> = digits.chars().collect();
+    for (i, c) in chars.iter().enumerate() {
+        if *c == '_' && (i == 0 || i == chars.len() - 1 || !chars[i - 1].is_digit(radix) || !chars[i + 1].is_digit(radix)) {
+            return Err("Separator '_' must be between digits.");
         }
-        self.chars().skip(begin_index).take(end_index - begin_index).collect()
     }
+    let digits: String = digits.chars().filter(|c| *c != '_').collect();
 
-    fn char_at(&mut self, index_pos: usize) -> char {
-        let curr_source_char : char =  match self.chars().nth(index_pos) {
-            Some(x) => x,
-            None => {
-                println!("advance(): char not accessible by index. Returning empty space. Index: {}", index_pos);
-                return ' '
-            }
+    if radix == 10 {
+        return digits.parse::<Number>().map_err(|_| "Invalid number literal.");
+    }
+    if digits.is_empty() {
+        return Err("Expect digits after number prefix.");
+    }
+    if !digits.chars().all(|c| c.is_digit(radix)) {
+        return match radix {
+            16 => Err("Invalid digit in hexadecimal number."),
+            8 => Err("Invalid digit in octal number."),
+            _ => Err("Invalid digit in binary number.")
         };
-
-        curr_source_char
     }
-}
\ No newline at end of file
+    u64::from_str_radix(&digits, radix).map(|n| n as Number).map_err(|_| "Number literal is too large.")
+}
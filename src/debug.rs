@@ -32,9 +32,10 @@ pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> usize{
         OpCode::OpTrue => simple_instruction("OpTrue", offset),
         OpCode::OpFalse => simple_instruction("OpFalse", offset),
         OpCode::OpPop => simple_instruction("OpPop", offset),
+        OpCode::OpSwap => simple_instruction("OpSwap", offset),
+        OpCode::OpOver => simple_instruction("OpOver", offset),
         OpCode::OpGetLocal => byte_instruction("OpGetLocal", chunk, offset),
         OpCode::OpSetLocal => byte_instruction("OpSetLocal", chunk, offset),
-        OpCode::OpSetConstLocal => constant_instruction("OpSetConstLocal", chunk,  offset),
         OpCode::OpGetGlobal => constant_instruction("OpGetGlobal", chunk,  offset),
         OpCode::OpDefineGlobal => constant_instruction("OpDefineGlobal", chunk, offset),
         OpCode::OpSetGlobal => constant_instruction("OpSetGlobal", chunk,  offset),
@@ -51,10 +52,16 @@ pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> usize{
         OpCode::OpNot => simple_instruction("OpNot", offset),
         OpCode::OpNegate => simple_instruction("OpNegate", offset),
         OpCode::OpPrint => simple_instruction("OpPrint", offset),
+        OpCode::OpAssert => constant_instruction("OpAssert", chunk, offset),
+        OpCode::OpDefer => byte_instruction("OpDefer", chunk, offset),
+        OpCode::OpDeferred => byte_instruction("OpDeferred", chunk, offset),
         OpCode::OpJump => jump_instruction("OpJump", 1, chunk, offset),
         OpCode::OpJumpIfFalse => jump_instruction("OpJumpIfFalse", 1, chunk, offset),
         OpCode::OpLoop => jump_instruction("OpLoop", -1, chunk, offset),
         OpCode::OpCall => byte_instruction("OpCall", chunk, offset),
+        OpCode::OpTailCall => byte_instruction("OpTailCall", chunk, offset),
+        OpCode::OpInvoke => invoke_instruction("OpInvoke", chunk, offset),
+        OpCode::OpGetProperty => constant_instruction("OpGetProperty", chunk, offset),
         OpCode::OpReturn => simple_instruction("OpReturn", offset),
         _ => {
             println!("Unknown opcode {:#?}", instruction);
@@ -74,6 +81,17 @@ fn constant_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize{
 
 }
 
+#[cfg(any(feature = "debug_trace_execution", feature = "debug_print_code"))]
+fn invoke_instruction(name: &str, chunk: &Chunk, offset: usize) -> usize{
+    // the method name is a constant, followed by the number of arguments
+    let constant_index: u8 = chunk.code[offset + 1];
+    let arg_count: u8 = chunk.code[offset + 2];
+    print!("{name:-16} ({arg_count} args) {constant_index:4} '");
+    print!("{}",chunk.constants[constant_index as usize]);
+    println!("'");
+    return offset + 3;
+}
+
 #[cfg(any(feature = "debug_trace_execution", feature = "debug_print_code"))]
 fn simple_instruction(name: &str, offset: usize) -> usize{
     println!("{} ", name);
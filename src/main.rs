@@ -12,26 +12,47 @@ mod compiler;
 mod scanner;
 mod token_type;
 mod precedence;
+mod methods;
+mod natives;
+mod persistent;
 
 use vm::*;
+use compiler::CompilerOptions;
 
 fn main() {
     env::set_var("RUST_BACKTRACE", "1");
     let mut vm: VM = VM::new();
-    let args: Vec<_> = env::args().collect();
+    let mut options = CompilerOptions::default();
+    let mut virtual_clock = false;
+    let mut args: Vec<String> = Vec::new();
+    // Flags can be passed before or after the path, e.g. 'cargo run -- --strip-asserts test.lox'
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--strip-asserts" => options.strip_asserts = true,
+            "--virtual-clock" => virtual_clock = true,
+            "--optional-semicolons" => options.optional_semicolons = true,
+            "--strict" => options.strict = true,
+            "--no-contracts" => options.strip_contracts = true,
+            _ => args.push(arg)
+        }
+    }
+    vm.set_compiler_options(options);
+    if virtual_clock {
+        vm.use_virtual_clock();
+    }
     // Returns error if too many arguments passed
-    if args.len() > 2 {
-        println!("Usage: clox [path]\n");
+    if args.len() > 1 {
+        println!("Usage: clox [--strip-asserts] [--virtual-clock] [--optional-semicolons] [--strict] [--no-contracts] [path]\n");
         std::process::exit(64);
     }
     // Runs the file of the directory of the second command
     // do 'cargo run test.lox', for example
-    else if args.len() == 2 {
-        run_file(&args[1], &mut vm);
+    else if args.len() == 1 {
+        run_file(&args[0], &mut vm);
     }
     // If no arguments are passed, run REPL 
     else {
-        repl(&mut vm);
+        repl(options, virtual_clock);
     }
 }
 
@@ -52,10 +73,14 @@ line by line, and executes the compiler with it. It uses a string that
 stores the user's lines, one by one, continuously, and with that it can 
 execute anything the compiler can, directly in the terminal.
 */
-fn repl(_vm: &mut VM) {
+fn repl(options: CompilerOptions, virtual_clock: bool) {
     let mut line: String = String::new();
     loop{
         let mut vm: VM = VM::new();
+        vm.set_compiler_options(options);
+        if virtual_clock {
+            vm.use_virtual_clock();
+        }
         print!(">> ");
         io::stdout().flush().unwrap();
         io::stdin().read_line(&mut line).expect("Could not read the line");
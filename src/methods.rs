@@ -0,0 +1,245 @@
+// Methods of the built-in types, like "abc".len() or n.floor()
+// The VM registers these in its method table, keyed by the type name of the receiver
+use crate::persistent::{Map, Vector};
+use crate::value::*;
+
+// Methods that can be called on strings
+pub fn string_methods() -> Vec<(&'static str, NativeMethod)> {
+    vec![
+        ("len", string_len),
+        ("upper", string_upper),
+        ("lower", string_lower),
+        ("trim", string_trim),
+        ("contains", string_contains),
+        ("starts_with", string_starts_with),
+        ("ends_with", string_ends_with),
+        ("index_of", string_index_of),
+        ("substring", string_substring),
+        ("replace", string_replace),
+        ("repeat", string_repeat),
+        ("split", string_split),
+    ]
+}
+
+// Methods that can be called on numbers
+pub fn number_methods() -> Vec<(&'static str, NativeMethod)> {
+    vec![
+        ("floor", number_floor),
+        ("ceil", number_ceil),
+        ("round", number_round),
+        ("abs", number_abs),
+        ("sqrt", number_sqrt),
+        ("min", number_min),
+        ("max", number_max),
+        ("to_string", number_to_string),
+    ]
+}
+
+// Methods that can be called on persistent vectors
+pub fn vector_methods() -> Vec<(&'static str, NativeMethod)> {
+    vec![
+        ("len", vector_len),
+    ]
+}
+
+// Methods that can be called on persistent maps
+pub fn map_methods() -> Vec<(&'static str, NativeMethod)> {
+    vec![
+        ("len", map_len),
+        ("contains", map_contains),
+    ]
+}
+
+/*
+    Helpers to check the arguments of a method
+*/
+fn check_arity(name: &str, expected: usize, args: &[Value]) -> Result<(), String> {
+    if args.len() != expected {
+        return Err(format!("{}() expected {} arguments but got {}.", name, expected, args.len()));
+    }
+    Ok(())
+}
+
+fn string_arg(name: &str, args: &[Value], index: usize) -> Result<String, String> {
+    match &args[index] {
+        Value::String(_string) => Ok(_string.clone()),
+        other => Err(format!("{}() expected a string argument but got {}.", name, other.type_name()))
+    }
+}
+
+fn number_arg(name: &str, args: &[Value], index: usize) -> Result<Number, String> {
+    match &args[index] {
+        Value::Number(_number) => Ok(*_number),
+        other => Err(format!("{}() expected a number argument but got {}.", name, other.type_name()))
+    }
+}
+
+// Converts a number argument into a count or an index, which can't be negative or have a fraction
+fn index_arg(name: &str, args: &[Value], index: usize) -> Result<usize, String> {
+    let _number = number_arg(name, args, index)?;
+    if _number < 0.0 || _number.fract() != 0.0 {
+        return Err(format!("{}() expected a non-negative integer but got {}.", name, _number));
+    }
+    Ok(_number as usize)
+}
+
+/*
+    String methods
+    Lengths and indexes count characters, not bytes
+*/
+fn string_len(receiver: &Value, args: &[Value]) -> Result<Value, String> {
+    check_arity("len", 0, args)?;
+    let _string: String = receiver.clone().into();
+    Ok(Value::from(_string.chars().count() as Number))
+}
+
+fn string_upper(receiver: &Value, args: &[Value]) -> Result<Value, String> {
+    check_arity("upper", 0, args)?;
+    let _string: String = receiver.clone().into();
+    Ok(Value::from(_string.to_uppercase()))
+}
+
+fn string_lower(receiver: &Value, args: &[Value]) -> Result<Value, String> {
+    check_arity("lower", 0, args)?;
+    let _string: String = receiver.clone().into();
+    Ok(Value::from(_string.to_lowercase()))
+}
+
+fn string_trim(receiver: &Value, args: &[Value]) -> Result<Value, String> {
+    check_arity("trim", 0, args)?;
+    let _string: String = receiver.clone().into();
+    Ok(Value::from(_string.trim().to_string()))
+}
+
+fn string_contains(receiver: &Value, args: &[Value]) -> Result<Value, String> {
+    check_arity("contains", 1, args)?;
+    let _string: String = receiver.clone().into();
+    Ok(Value::from(_string.contains(&string_arg("contains", args, 0)?)))
+}
+
+fn string_starts_with(receiver: &Value, args: &[Value]) -> Result<Value, String> {
+    check_arity("starts_with", 1, args)?;
+    let _string: String = receiver.clone().into();
+    Ok(Value::from(_string.starts_with(&string_arg("starts_with", args, 0)?)))
+}
+
+fn string_ends_with(receiver: &Value, args: &[Value]) -> Result<Value, String> {
+    check_arity("ends_with", 1, args)?;
+    let _string: String = receiver.clone().into();
+    Ok(Value::from(_string.ends_with(&string_arg("ends_with", args, 0)?)))
+}
+
+// Returns the index of the first match, or -1 if there is none
+fn string_index_of(receiver: &Value, args: &[Value]) -> Result<Value, String> {
+    check_arity("index_of", 1, args)?;
+    let _string: String = receiver.clone().into();
+    let index = match _string.find(&string_arg("index_of", args, 0)?) {
+        Some(byte_index) => _string[..byte_index].chars().count() as Number,
+        None => -1.0
+    };
+    Ok(Value::from(index))
+}
+
+// Returns the characters from start up to, but not including, end
+fn string_substring(receiver: &Value, args: &[Value]) -> Result<Value, String> {
+    check_arity("substring", 2, args)?;
+    let _string: String = receiver.clone().into();
+    let start = index_arg("substring", args, 0)?;
+    let end = index_arg("substring", args, 1)?;
+    let length = _string.chars().count();
+    if start > end || end > length {
+        return Err(format!("substring() range {}..{} is out of bounds for length {}.", start, end, length));
+    }
+    Ok(Value::from(_string.chars().skip(start).take(end - start).collect::<String>()))
+}
+
+fn string_replace(receiver: &Value, args: &[Value]) -> Result<Value, String> {
+    check_arity("replace", 2, args)?;
+    let _string: String = receiver.clone().into();
+    let from = string_arg("replace", args, 0)?;
+    let to = string_arg("replace", args, 1)?;
+    Ok(Value::from(_string.replace(&from, &to)))
+}
+
+fn string_repeat(receiver: &Value, args: &[Value]) -> Result<Value, String> {
+    check_arity("repeat", 1, args)?;
+    let _string: String = receiver.clone().into();
+    Ok(Value::from(_string.repeat(index_arg("repeat", args, 0)?)))
+}
+
+// Returns a vector of the parts between the separators
+fn string_split(receiver: &Value, args: &[Value]) -> Result<Value, String> {
+    check_arity("split", 1, args)?;
+    let _string: String = receiver.clone().into();
+    let separator = string_arg("split", args, 0)?;
+    if separator.is_empty() {
+        return Err("split() expected a non-empty separator.".to_string());
+    }
+    let parts: Vec<Value> = _string.split(separator.as_str()).map(|part| Value::from(part.to_string())).collect();
+    Ok(Value::Vector(Vector::from_values(&parts)))
+}
+
+/*
+    Number methods
+*/
+fn number_floor(receiver: &Value, args: &[Value]) -> Result<Value, String> {
+    check_arity("floor", 0, args)?;
+    Ok(Value::from(Number::from(receiver.clone()).floor()))
+}
+
+fn number_ceil(receiver: &Value, args: &[Value]) -> Result<Value, String> {
+    check_arity("ceil", 0, args)?;
+    Ok(Value::from(Number::from(receiver.clone()).ceil()))
+}
+
+fn number_round(receiver: &Value, args: &[Value]) -> Result<Value, String> {
+    check_arity("round", 0, args)?;
+    Ok(Value::from(Number::from(receiver.clone()).round()))
+}
+
+fn number_abs(receiver: &Value, args: &[Value]) -> Result<Value, String> {
+    check_arity("abs", 0, args)?;
+    Ok(Value::from(Number::from(receiver.clone()).abs()))
+}
+
+fn number_sqrt(receiver: &Value, args: &[Value]) -> Result<Value, String> {
+    check_arity("sqrt", 0, args)?;
+    Ok(Value::from(Number::from(receiver.clone()).sqrt()))
+}
+
+fn number_min(receiver: &Value, args: &[Value]) -> Result<Value, String> {
+    check_arity("min", 1, args)?;
+    Ok(Value::from(Number::from(receiver.clone()).min(number_arg("min", args, 0)?)))
+}
+
+fn number_max(receiver: &Value, args: &[Value]) -> Result<Value, String> {
+    check_arity("max", 1, args)?;
+    Ok(Value::from(Number::from(receiver.clone()).max(number_arg("max", args, 0)?)))
+}
+
+fn number_to_string(receiver: &Value, args: &[Value]) -> Result<Value, String> {
+    check_arity("to_string", 0, args)?;
+    Ok(Value::from(receiver.to_string()))
+}
+
+/*
+    Vector and map methods
+*/
+fn vector_len(receiver: &Value, args: &[Value]) -> Result<Value, String> {
+    check_arity("len", 0, args)?;
+    let _vector: Vector = receiver.clone().into();
+    Ok(Value::from(_vector.len() as Number))
+}
+
+fn map_len(receiver: &Value, args: &[Value]) -> Result<Value, String> {
+    check_arity("len", 0, args)?;
+    let _map: Map = receiver.clone().into();
+    Ok(Value::from(_map.len() as Number))
+}
+
+// Whether the map has the key, even when its value is nil
+fn map_contains(receiver: &Value, args: &[Value]) -> Result<Value, String> {
+    check_arity("contains", 1, args)?;
+    let _map: Map = receiver.clone().into();
+    Ok(Value::from(_map.get(&args[0]).is_some()))
+}
@@ -0,0 +1,381 @@
+// Native functions the VM defines as globals
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::persistent::{Map, Vector};
+use crate::value::*;
+use crate::vm::VM;
+
+pub struct NativeClock{}
+
+// Implements NativeFn trait for NativeClock
+impl NativeFn for NativeClock{
+    // CITE: UncleScientist lox-bytecode repo in Github
+    // CITE: https://github.com/UncleScientist/lox-bytecode
+    fn fun_call(&self, vm: &mut VM, _arg_count: usize, _args: &[Value]) -> Result<Value, NativeError> {
+        // With a virtual clock, the time is the milliseconds on the virtual clock instead
+        if let Some(time) = vm.virtual_time() {
+            return Ok(Value::Number(time));
+        }
+        match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH){
+            Ok(time) => Ok(Value::Number(time.as_millis() as f64)),
+            Err(_) => panic!("Can't get system time")
+        }
+    }
+}
+
+pub struct NativeHelp{}
+
+// Prints the name, arity and documentation of a function or native
+impl NativeFn for NativeHelp{
+    fn fun_call(&self, _vm: &mut VM, _arg_count: usize, args: &[Value]) -> Result<Value, NativeError> {
+        let (kind, name, arity, doc) = match &args[0] {
+            Value::Fun(_function) => (
+                "fun",
+                _function.name.clone().unwrap_or("script".to_string()),
+                _function.arity.to_string(),
+                _function.doc.clone()
+            ),
+            Value::Native(_native) => (
+                "native",
+                _native.name.clone(),
+                _native.arity.map_or("any".to_string(), |arity| arity.to_string()),
+                _native.doc.clone()
+            ),
+            other => return Err(format!("help() expected a function but got {}.", other.type_name()).into())
+        };
+
+        println!("{} {}, arity {}", kind, name, arity);
+        println!("{}", doc.unwrap_or("No documentation.".to_string()));
+        Ok(Value::Nil)
+    }
+}
+
+pub struct NativeTypeOf{}
+
+// Returns the name of the type of any value
+impl NativeFn for NativeTypeOf{
+    fn fun_call(&self, _vm: &mut VM, _arg_count: usize, args: &[Value]) -> Result<Value, NativeError> {
+        Ok(Value::from(args[0].type_name().to_string()))
+    }
+}
+
+pub struct NativeArity{}
+
+// Returns the number of parameters of a function, or nil for natives that take any number of arguments
+impl NativeFn for NativeArity{
+    fn fun_call(&self, _vm: &mut VM, _arg_count: usize, args: &[Value]) -> Result<Value, NativeError> {
+        match &args[0] {
+            Value::Fun(_function) => Ok(Value::from(_function.arity as Number)),
+            Value::Native(_native) => Ok(_native.arity.map_or(Value::Nil, |arity| Value::from(arity as Number))),
+            other => Err(format!("arity() expected a function but got {}.", other.type_name()).into())
+        }
+    }
+}
+
+pub struct NativeNameOf{}
+
+// Returns the name of a function
+impl NativeFn for NativeNameOf{
+    fn fun_call(&self, _vm: &mut VM, _arg_count: usize, args: &[Value]) -> Result<Value, NativeError> {
+        match &args[0] {
+            Value::Fun(_function) => Ok(Value::from(_function.name.clone().unwrap_or("script".to_string()))),
+            Value::Native(_native) => Ok(Value::from(_native.name.clone())),
+            other => Err(format!("name_of() expected a function but got {}.", other.type_name()).into())
+        }
+    }
+}
+
+pub struct NativeGlobals{}
+
+// Returns every defined global and its value, one per line and sorted by name
+impl NativeFn for NativeGlobals{
+    fn fun_call(&self, vm: &mut VM, _arg_count: usize, _args: &[Value]) -> Result<Value, NativeError> {
+        let mut names: Vec<&String> = vm.globals().keys().collect();
+        names.sort();
+
+        let lines: Vec<String> = names.iter().map(|name| {
+            let global = &vm.globals()[*name];
+            let keyword = if global.constant { "const" } else { "var" };
+            format!("{} {} = {}", keyword, name, global.value)
+        }).collect();
+        Ok(Value::from(lines.join("\n")))
+    }
+}
+
+pub struct NativeDefined{}
+
+// Returns whether a global with the given name is defined
+impl NativeFn for NativeDefined{
+    fn fun_call(&self, vm: &mut VM, _arg_count: usize, args: &[Value]) -> Result<Value, NativeError> {
+        match &args[0] {
+            Value::String(name) => Ok(Value::from(vm.globals().contains_key(name))),
+            other => Err(format!("defined() expected a string but got {}.", other.type_name()).into())
+        }
+    }
+}
+
+// Checks the arguments of set_timeout and set_interval, which are a function and a delay in milliseconds
+fn timer_args(name: &str, args: &[Value]) -> Result<(Value, Number), String> {
+    let callback = match &args[0] {
+        Value::Fun(_) | Value::Native(_) => args[0].clone(),
+        other => return Err(format!("{}() expected a function but got {}.", name, other.type_name()))
+    };
+    match &args[1] {
+        Value::Number(_delay) if *_delay >= 0.0 => Ok((callback, *_delay)),
+        Value::Number(_delay) => Err(format!("{}() expected a non-negative delay but got {}.", name, _delay)),
+        other => Err(format!("{}() expected a number of milliseconds but got {}.", name, other.type_name()))
+    }
+}
+
+pub struct NativeSetTimeout{}
+
+// Schedules a function to be called once by the event loop
+impl NativeFn for NativeSetTimeout{
+    fn fun_call(&self, vm: &mut VM, _arg_count: usize, args: &[Value]) -> Result<Value, NativeError> {
+        let (callback, delay) = timer_args("set_timeout", args)?;
+        Ok(Value::from(vm.schedule(callback, delay, false) as Number))
+    }
+}
+
+pub struct NativeSetInterval{}
+
+// Schedules a function to be called by the event loop every interval, until it is cancelled
+impl NativeFn for NativeSetInterval{
+    fn fun_call(&self, vm: &mut VM, _arg_count: usize, args: &[Value]) -> Result<Value, NativeError> {
+        let (callback, interval) = timer_args("set_interval", args)?;
+        // An interval of 0 would keep the event loop from ever moving on
+        if interval == 0.0 {
+            return Err("set_interval() expected an interval greater than 0.".into());
+        }
+        Ok(Value::from(vm.schedule(callback, interval, true) as Number))
+    }
+}
+
+pub struct NativeCancel{}
+
+// Cancels a timer by its id
+impl NativeFn for NativeCancel{
+    fn fun_call(&self, vm: &mut VM, _arg_count: usize, args: &[Value]) -> Result<Value, NativeError> {
+        match &args[0] {
+            Value::Number(_id) => Ok(Value::from(_id.fract() == 0.0 && *_id >= 0.0 && vm.cancel_timer(*_id as usize))),
+            other => Err(format!("cancel() expected a timer id but got {}.", other.type_name()).into())
+        }
+    }
+}
+
+pub struct NativeSpawn{}
+
+// Runs a function on another thread
+impl NativeFn for NativeSpawn{
+    fn fun_call(&self, vm: &mut VM, arg_count: usize, args: &[Value]) -> Result<Value, NativeError> {
+        if arg_count == 0 {
+            return Err("spawn() expected a function.".into());
+        }
+        match &args[0] {
+            Value::Fun(_) => Ok(vm.spawn(args[0].clone(), &args[1..])?),
+            other => Err(format!("spawn() expected a function but got {}.", other.type_name()).into())
+        }
+    }
+}
+
+pub struct NativeJoin{}
+
+// Waits for a worker and returns its result
+impl NativeFn for NativeJoin{
+    fn fun_call(&self, _vm: &mut VM, _arg_count: usize, args: &[Value]) -> Result<Value, NativeError> {
+        match &args[0] {
+            Value::Worker(_worker) => Ok(_worker.join()?),
+            other => Err(format!("join() expected a worker but got {}.", other.type_name()).into())
+        }
+    }
+}
+
+pub struct NativeChannel{}
+
+// Creates a channel
+impl NativeFn for NativeChannel{
+    fn fun_call(&self, _vm: &mut VM, _arg_count: usize, _args: &[Value]) -> Result<Value, NativeError> {
+        Ok(Value::Channel(Arc::new(Channel::default())))
+    }
+}
+
+pub struct NativeSend{}
+
+// Sends a copy of a value through a channel
+impl NativeFn for NativeSend{
+    fn fun_call(&self, _vm: &mut VM, _arg_count: usize, args: &[Value]) -> Result<Value, NativeError> {
+        match &args[0] {
+            Value::Channel(_channel) => {
+                _channel.send(args[1].to_sendable()?);
+                Ok(Value::Nil)
+            },
+            other => Err(format!("send() expected a channel but got {}.", other.type_name()).into())
+        }
+    }
+}
+
+pub struct NativeRecv{}
+
+// Receives a value from a channel, waiting for one if there is none yet
+impl NativeFn for NativeRecv{
+    fn fun_call(&self, _vm: &mut VM, _arg_count: usize, args: &[Value]) -> Result<Value, NativeError> {
+        match &args[0] {
+            Value::Channel(_channel) => Ok(_channel.recv()),
+            other => Err(format!("recv() expected a channel but got {}.", other.type_name()).into())
+        }
+    }
+}
+
+pub struct NativeSymbol{}
+
+// Returns the symbol with the name in a string, so symbol("ready") == :ready
+impl NativeFn for NativeSymbol{
+    fn fun_call(&self, _vm: &mut VM, _arg_count: usize, args: &[Value]) -> Result<Value, NativeError> {
+        match &args[0] {
+            Value::String(_name) if !_name.is_empty() => Ok(Value::Symbol(Symbol::intern(_name))),
+            Value::String(_) => Err("symbol() expected a name but got an empty string.".into()),
+            other => Err(format!("symbol() expected a string but got {}.", other.type_name()).into())
+        }
+    }
+}
+
+pub struct NativeSymbolName{}
+
+// Returns the name of a symbol as a string, without the ':'
+impl NativeFn for NativeSymbolName{
+    fn fun_call(&self, _vm: &mut VM, _arg_count: usize, args: &[Value]) -> Result<Value, NativeError> {
+        match &args[0] {
+            Value::Symbol(_symbol) => Ok(Value::from(_symbol.name().to_string())),
+            other => Err(format!("symbol_name() expected a symbol but got {}.", other.type_name()).into())
+        }
+    }
+}
+
+pub struct NativeVector{}
+
+// Creates a persistent vector of its arguments
+impl NativeFn for NativeVector{
+    fn fun_call(&self, _vm: &mut VM, _arg_count: usize, args: &[Value]) -> Result<Value, NativeError> {
+        Ok(Value::Vector(Vector::from_values(args)))
+    }
+}
+
+pub struct NativeHashMap{}
+
+// Creates a persistent map from keys each followed by their value, like hash_map(:name, "lox", :version, 2)
+impl NativeFn for NativeHashMap{
+    fn fun_call(&self, _vm: &mut VM, arg_count: usize, args: &[Value]) -> Result<Value, NativeError> {
+        if !arg_count.is_multiple_of(2) {
+            return Err("hash_map() expected a value for every key.".into());
+        }
+        let map = args.chunks(2).fold(Map::new(), |map, pair| map.insert(pair[0].clone(), pair[1].clone()));
+        Ok(Value::Map(map))
+    }
+}
+
+// Converts the index argument of a vector native, which can't be negative or have a fraction
+fn vector_index(name: &str, index: &Value) -> Result<usize, String> {
+    match index {
+        Value::Number(_index) if *_index >= 0.0 && _index.fract() == 0.0 => Ok(*_index as usize),
+        Value::Number(_index) => Err(format!("{}() expected a non-negative integer index but got {}.", name, _index)),
+        other => Err(format!("{}() expected a number index for a vector but got {}.", name, other.type_name()))
+    }
+}
+
+pub struct NativeConj{}
+
+// Returns a new vector with a value added at the end
+impl NativeFn for NativeConj{
+    fn fun_call(&self, _vm: &mut VM, _arg_count: usize, args: &[Value]) -> Result<Value, NativeError> {
+        match &args[0] {
+            Value::Vector(_vector) => Ok(Value::Vector(_vector.push(args[1].clone()))),
+            other => Err(format!("conj() expected a vector but got {}.", other.type_name()).into())
+        }
+    }
+}
+
+pub struct NativeAssoc{}
+
+// Returns a new map with a key set to a value, or a new vector with the value at an index replaced
+impl NativeFn for NativeAssoc{
+    fn fun_call(&self, _vm: &mut VM, _arg_count: usize, args: &[Value]) -> Result<Value, NativeError> {
+        match &args[0] {
+            Value::Map(_map) => Ok(Value::Map(_map.insert(args[1].clone(), args[2].clone()))),
+            Value::Vector(_vector) => {
+                let index = vector_index("assoc", &args[1])?;
+                // The index right after the end adds the value, like conj()
+                match index.cmp(&_vector.len()) {
+                    std::cmp::Ordering::Less => Ok(Value::Vector(_vector.set(index, args[2].clone()))),
+                    std::cmp::Ordering::Equal => Ok(Value::Vector(_vector.push(args[2].clone()))),
+                    std::cmp::Ordering::Greater => Err(format!("assoc() index {} is out of bounds for a vector of length {}.", index, _vector.len()).into())
+                }
+            },
+            other => Err(format!("assoc() expected a map or a vector but got {}.", other.type_name()).into())
+        }
+    }
+}
+
+pub struct NativeDissoc{}
+
+// Returns a new map without a key
+impl NativeFn for NativeDissoc{
+    fn fun_call(&self, _vm: &mut VM, _arg_count: usize, args: &[Value]) -> Result<Value, NativeError> {
+        match &args[0] {
+            Value::Map(_map) => Ok(Value::Map(_map.remove(&args[1]))),
+            other => Err(format!("dissoc() expected a map but got {}.", other.type_name()).into())
+        }
+    }
+}
+
+pub struct NativeGet{}
+
+// Returns the value of a key in a map or at an index in a vector, or nil if there is none
+impl NativeFn for NativeGet{
+    fn fun_call(&self, _vm: &mut VM, _arg_count: usize, args: &[Value]) -> Result<Value, NativeError> {
+        match &args[0] {
+            Value::Map(_map) => Ok(_map.get(&args[1]).cloned().unwrap_or(Value::Nil)),
+            Value::Vector(_vector) => {
+                let index = vector_index("get", &args[1])?;
+                Ok(_vector.get(index).cloned().unwrap_or(Value::Nil))
+            },
+            other => Err(format!("get() expected a map or a vector but got {}.", other.type_name()).into())
+        }
+    }
+}
+
+pub struct NativeMemoize{}
+
+// Wraps a function in a native that caches its results, usually used as a decorator: '@memoize fun fib(n) { ... }'
+impl NativeFn for NativeMemoize{
+    fn fun_call(&self, _vm: &mut VM, _arg_count: usize, args: &[Value]) -> Result<Value, NativeError> {
+        match &args[0] {
+            Value::Fun(_function) => Ok(Value::Native(Native {
+                name: _function.name.clone().unwrap_or("script".to_string()),
+                arity: Some(_function.arity),
+                doc: _function.doc.clone(),
+                function: Arc::new(Memoized { function: args[0].clone(), cache: Mutex::new(HashMap::new()) }),
+            })),
+            other => Err(format!("memoize() expected a function but got {}.", other.type_name()).into())
+        }
+    }
+}
+
+// A function along with the results of its earlier calls, by their arguments
+struct Memoized{
+    function: Value,
+    cache: Mutex<HashMap<Vec<Value>, Value>>,
+}
+
+impl NativeFn for Memoized{
+    fn fun_call(&self, vm: &mut VM, _arg_count: usize, args: &[Value]) -> Result<Value, NativeError> {
+        if let Some(result) = self.cache.lock().unwrap().get(args) {
+            return Ok(result.clone());
+        }
+        // The lock isn't held during the call, since the function can call itself through this native
+        let result = vm.call_function(self.function.clone(), args.to_vec()).ok_or(NativeError::Reported)?;
+        self.cache.lock().unwrap().insert(args.to_vec(), result.clone());
+        Ok(result)
+    }
+}
@@ -0,0 +1,323 @@
+/*
+Persistent vectors and maps.
+Changing one returns a new version and leaves the old one as it was. The two versions share everything
+but the path from the root to the change, so an update only copies a few small nodes
+*/
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
+use crate::value::Value;
+
+// Every node has up to 32 children, picked by the next 5 bits of the index or the hash
+const BITS: usize = 5;
+const WIDTH: usize = 1 << BITS;
+const MASK: usize = WIDTH - 1;
+
+#[derive(Debug)]
+enum VectorNode {
+    Branch(Vec<Arc<VectorNode>>),
+    Leaf(Vec<Value>),
+}
+
+// A vector, kept in a tree where the leaves hold the values in order
+#[derive(Debug, Clone)]
+pub struct Vector {
+    len: usize,
+    // The bits of an index below the ones the root uses, 0 when the root is a leaf
+    shift: usize,
+    root: Arc<VectorNode>,
+}
+
+impl Vector {
+    pub fn new() -> Self {
+        Vector { len: 0, shift: 0, root: Arc::new(VectorNode::Leaf(Vec::new())) }
+    }
+
+    pub fn from_values(values: &[Value]) -> Self {
+        values.iter().fold(Vector::new(), |vector, value| vector.push(value.clone()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Value> {
+        if index >= self.len { return None; }
+        let mut node = &self.root;
+        let mut shift = self.shift;
+        loop {
+            match node.as_ref() {
+                VectorNode::Branch(children) => {
+                    node = &children[(index >> shift) & MASK];
+                    shift -= BITS;
+                },
+                VectorNode::Leaf(values) => return values.get(index & MASK)
+            }
+        }
+    }
+
+    // Returns a new version with the value at an index replaced, the index has to be in the vector
+    pub fn set(&self, index: usize, value: Value) -> Self {
+        Vector { len: self.len, shift: self.shift, root: Arc::new(Vector::set_in(&self.root, self.shift, index, value)) }
+    }
+
+    fn set_in(node: &VectorNode, shift: usize, index: usize, value: Value) -> VectorNode {
+        match node {
+            VectorNode::Branch(children) => {
+                let mut children = children.clone();
+                let child = (index >> shift) & MASK;
+                children[child] = Arc::new(Vector::set_in(&children[child], shift - BITS, index, value));
+                VectorNode::Branch(children)
+            },
+            VectorNode::Leaf(values) => {
+                let mut values = values.clone();
+                values[index & MASK] = value;
+                VectorNode::Leaf(values)
+            }
+        }
+    }
+
+    // Returns a new version with a value added at the end
+    pub fn push(&self, value: Value) -> Self {
+        // When the tree is full, the old root and a new path for the value become the children of a new root
+        if self.len == 1 << (self.shift + BITS) {
+            let root = VectorNode::Branch(vec![self.root.clone(), Arc::new(Vector::new_path(self.shift, value))]);
+            return Vector { len: self.len + 1, shift: self.shift + BITS, root: Arc::new(root) };
+        }
+        Vector { len: self.len + 1, shift: self.shift, root: Arc::new(Vector::push_in(&self.root, self.shift, self.len, value)) }
+    }
+
+    fn push_in(node: &VectorNode, shift: usize, index: usize, value: Value) -> VectorNode {
+        match node {
+            VectorNode::Branch(children) => {
+                let mut children = children.clone();
+                let child = (index >> shift) & MASK;
+                if child < children.len() {
+                    children[child] = Arc::new(Vector::push_in(&children[child], shift - BITS, index, value));
+                } else {
+                    children.push(Arc::new(Vector::new_path(shift - BITS, value)));
+                }
+                VectorNode::Branch(children)
+            },
+            VectorNode::Leaf(values) => {
+                let mut values = values.clone();
+                values.push(value);
+                VectorNode::Leaf(values)
+            }
+        }
+    }
+
+    // A branch of nodes with one child each, down to a leaf with the value
+    fn new_path(shift: usize, value: Value) -> VectorNode {
+        if shift == 0 {
+            return VectorNode::Leaf(vec![value]);
+        }
+        VectorNode::Branch(vec![Arc::new(Vector::new_path(shift - BITS, value))])
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Value> {
+        (0..self.len).map(move |index| self.get(index).unwrap())
+    }
+}
+
+impl PartialEq for Vector {
+    fn eq(&self, other: &Self) -> bool {
+        self.len == other.len && self.iter().zip(other.iter()).all(|(a, b)| a == b)
+    }
+}
+
+impl Hash for Vector {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.len.hash(state);
+        for value in self.iter() {
+            value.hash(state);
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum MapEntry {
+    // The keys with this hash along with their values, there is more than one only when hashes collide
+    Leaf(u64, Vec<(Value, Value)>),
+    Node(Arc<MapNode>),
+}
+
+// A node of a hash array mapped trie, which only keeps the children that are there
+// A child's bit is set in the bitmap, and the bits set before it give its position in the entries
+#[derive(Debug, Default)]
+struct MapNode {
+    bitmap: u32,
+    entries: Vec<MapEntry>,
+}
+
+impl MapNode {
+    // Returns the bit of the child for a hash and the position that child has in the entries
+    fn position(&self, hash: u64, shift: usize) -> (u32, usize) {
+        let bit = 1 << ((hash >> shift) as usize & MASK);
+        (bit, (self.bitmap & (bit - 1)).count_ones() as usize)
+    }
+
+    fn get(&self, hash: u64, shift: usize, key: &Value) -> Option<&Value> {
+        let (bit, index) = self.position(hash, shift);
+        if self.bitmap & bit == 0 { return None; }
+        match &self.entries[index] {
+            MapEntry::Leaf(leaf_hash, pairs) if *leaf_hash == hash => {
+                pairs.iter().find(|(pair_key, _)| pair_key == key).map(|(_, value)| value)
+            },
+            MapEntry::Leaf(_, _) => None,
+            MapEntry::Node(node) => node.get(hash, shift + BITS, key)
+        }
+    }
+
+    // Returns a new version of the node with the key set to the value, and whether the key wasn't there before
+    fn insert(&self, hash: u64, shift: usize, key: Value, value: Value) -> (MapNode, bool) {
+        let (bit, index) = self.position(hash, shift);
+        let mut entries = self.entries.clone();
+        if self.bitmap & bit == 0 {
+            entries.insert(index, MapEntry::Leaf(hash, vec![(key, value)]));
+            return (MapNode { bitmap: self.bitmap | bit, entries }, true);
+        }
+
+        let added = match &self.entries[index] {
+            MapEntry::Leaf(leaf_hash, pairs) if *leaf_hash == hash => {
+                let mut pairs = pairs.clone();
+                let added = match pairs.iter().position(|(pair_key, _)| *pair_key == key) {
+                    Some(pair) => {
+                        pairs[pair].1 = value;
+                        false
+                    },
+                    None => {
+                        pairs.push((key, value));
+                        true
+                    }
+                };
+                entries[index] = MapEntry::Leaf(hash, pairs);
+                added
+            },
+            // Another hash with the same bits so far, so both move down into a new node where their bits differ
+            MapEntry::Leaf(leaf_hash, pairs) => {
+                let (leaf_bit, _) = MapNode::default().position(*leaf_hash, shift + BITS);
+                let node = MapNode { bitmap: leaf_bit, entries: vec![MapEntry::Leaf(*leaf_hash, pairs.clone())] };
+                let (node, _) = node.insert(hash, shift + BITS, key, value);
+                entries[index] = MapEntry::Node(Arc::new(node));
+                true
+            },
+            MapEntry::Node(node) => {
+                let (node, added) = node.insert(hash, shift + BITS, key, value);
+                entries[index] = MapEntry::Node(Arc::new(node));
+                added
+            }
+        };
+        (MapNode { bitmap: self.bitmap, entries }, added)
+    }
+
+    // Returns a new version of the node without the key, or None if the key isn't there
+    fn remove(&self, hash: u64, shift: usize, key: &Value) -> Option<MapNode> {
+        let (bit, index) = self.position(hash, shift);
+        if self.bitmap & bit == 0 { return None; }
+        let mut entries = self.entries.clone();
+        let mut bitmap = self.bitmap;
+
+        let emptied = match &self.entries[index] {
+            MapEntry::Leaf(leaf_hash, pairs) => {
+                if *leaf_hash != hash { return None; }
+                let pair = pairs.iter().position(|(pair_key, _)| pair_key == key)?;
+                let mut pairs = pairs.clone();
+                pairs.remove(pair);
+                let emptied = pairs.is_empty();
+                entries[index] = MapEntry::Leaf(hash, pairs);
+                emptied
+            },
+            MapEntry::Node(node) => {
+                let node = node.remove(hash, shift + BITS, key)?;
+                let emptied = node.entries.is_empty();
+                entries[index] = MapEntry::Node(Arc::new(node));
+                emptied
+            }
+        };
+        if emptied {
+            entries.remove(index);
+            bitmap &= !bit;
+        }
+        Some(MapNode { bitmap, entries })
+    }
+
+    fn collect_pairs<'a>(&'a self, pairs: &mut Vec<(&'a Value, &'a Value)>) {
+        for entry in &self.entries {
+            match entry {
+                MapEntry::Leaf(_, leaf_pairs) => pairs.extend(leaf_pairs.iter().map(|(key, value)| (key, value))),
+                MapEntry::Node(node) => node.collect_pairs(pairs)
+            }
+        }
+    }
+}
+
+// A map from any value to any value, kept in a trie by the hashes of the keys
+#[derive(Debug, Clone)]
+pub struct Map {
+    len: usize,
+    root: Arc<MapNode>,
+}
+
+fn hash_of(key: &Value) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl Map {
+    pub fn new() -> Self {
+        Map { len: 0, root: Arc::new(MapNode::default()) }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn get(&self, key: &Value) -> Option<&Value> {
+        self.root.get(hash_of(key), 0, key)
+    }
+
+    // Returns a new version with the key set to the value
+    pub fn insert(&self, key: Value, value: Value) -> Self {
+        let (root, added) = self.root.insert(hash_of(&key), 0, key, value);
+        Map { len: self.len + added as usize, root: Arc::new(root) }
+    }
+
+    // Returns a new version without the key
+    pub fn remove(&self, key: &Value) -> Self {
+        match self.root.remove(hash_of(key), 0, key) {
+            Some(root) => Map { len: self.len - 1, root: Arc::new(root) },
+            None => self.clone()
+        }
+    }
+
+    // The keys and values, in the order of their hashes
+    pub fn pairs(&self) -> Vec<(&Value, &Value)> {
+        let mut pairs = Vec::with_capacity(self.len);
+        self.root.collect_pairs(&mut pairs);
+        pairs
+    }
+}
+
+impl PartialEq for Map {
+    fn eq(&self, other: &Self) -> bool {
+        self.len == other.len && self.pairs().iter().all(|(key, value)| other.get(key) == Some(value))
+    }
+}
+
+// The pairs are hashed on their own and added up, so maps with the same pairs hash the same however they were built
+impl Hash for Map {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let mut sum: u64 = 0;
+        for (key, value) in self.pairs() {
+            let mut hasher = DefaultHasher::new();
+            key.hash(&mut hasher);
+            value.hash(&mut hasher);
+            sum = sum.wrapping_add(hasher.finish());
+        }
+        self.len.hash(state);
+        sum.hash(state);
+    }
+}
@@ -2,6 +2,7 @@
 pub enum Precedence {
     PrecNone = 0,
     PrecAssignment,   // =
+    PrecPipe,         // |>
     PrecOr,           // or
     PrecAnd,          // and
     PrecEquality,     // == !=
@@ -18,15 +19,16 @@ impl From<usize> for Precedence {
         match value {
             0 => Precedence::PrecNone,
             1 => Precedence::PrecAssignment,
-            2 => Precedence::PrecOr,
-            3 => Precedence::PrecAnd,
-            4 => Precedence::PrecEquality,
-            5 => Precedence::PrecComparison,
-            6 => Precedence::PrecTerm,
-            7 => Precedence::PrecFactor,
-            8 => Precedence::PrecUnary,
-            9 => Precedence::PrecCall,
-            10 => Precedence::PrecPrimary,
+            2 => Precedence::PrecPipe,
+            3 => Precedence::PrecOr,
+            4 => Precedence::PrecAnd,
+            5 => Precedence::PrecEquality,
+            6 => Precedence::PrecComparison,
+            7 => Precedence::PrecTerm,
+            8 => Precedence::PrecFactor,
+            9 => Precedence::PrecUnary,
+            10 => Precedence::PrecCall,
+            11 => Precedence::PrecPrimary,
             _ => panic!("{value} can't be converted into Precedence")
         }
     }
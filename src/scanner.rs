@@ -4,13 +4,26 @@ pub struct Scanner {
     start : usize,
     current : usize,
     line: usize,
+    // Lines of the '///' comments right before the last scanned token
+    doc_comment: Vec<String>,
+    // Whether a newline was skipped before the last scanned token
+    newline_before: bool,
+    // Symbols of the operators declared with 'infix', longest first
+    operators: Vec<String>,
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Token {
     pub _type : TokenType,
     pub lexeme: String,
-    pub line : usize
+    pub line : usize,
+    // A newline comes between this token and the one before it
+    pub newline_before: bool,
+    // The macro expansion the token was written in, 0 for the tokens of the source
+    // Names only refer to locals declared with a name from the same expansion
+    pub expansion: usize,
+    // Where the token starts in the source, None for the tokens a macro expansion adds
+    pub offset: Option<usize>,
 }
 
 impl Scanner {
@@ -19,7 +32,10 @@ impl Scanner {
             source: String::new(),
             start: 0,
             current: 0,
-            line: 1
+            line: 1,
+            doc_comment: Vec::new(),
+            newline_before: false,
+            operators: Vec::new(),
         }
     }
 
@@ -27,7 +43,9 @@ impl Scanner {
     Scans the current token
     */
     pub fn scan_token(&mut self) -> Token {
-        self.skip_white_space();
+        let line = self.line;
+        if let Some(error) = self.skip_white_space() { return error; }
+        self.newline_before = self.line > line;
 
         self.start = self.current;
 
@@ -35,9 +53,23 @@ impl Scanner {
 
         let curr_char: char = self.advance();
 
+        // r"..." is a raw string
+        if curr_char == 'r' && self.peek() == '"' {
+            self.advance();
+            return self.string(true);
+        }
         if self.is_alpha(curr_char) { return self.identifier(); }
         if self.is_digit(curr_char) { return self.number(); }
 
+        // Declared operators take priority, so '<+>' isn't scanned as '<' followed by '+'
+        let declared = self.operators.iter()
+            .find(|symbol| self.source[self.start..].starts_with(symbol.as_str()))
+            .map(|symbol| symbol.len());
+        if let Some(length) = declared {
+            self.current = self.start + length;
+            return self.make_token(TokenType::TokenOperator);
+        }
+
         match curr_char {
             '(' => return self.make_token(TokenType::TokenLeftParen),
             ')' => return self.make_token(TokenType::TokenRightParen),
@@ -45,6 +77,7 @@ impl Scanner {
             '}' => return self.make_token(TokenType::TokenRightBrace),
             ';' => return self.make_token(TokenType::TokenSemicolon),
             ',' => return self.make_token(TokenType::TokenComma),
+            '@' => return self.make_token(TokenType::TokenAt),
             '.' => return self.make_token(TokenType::TokenDot),
             '-' => {
                 if self.matching('=') {
@@ -117,7 +150,22 @@ impl Scanner {
                     return self.make_token(TokenType::TokenGreater);
                 }
             }
-            '"' => return self.string(),
+            '|' => {
+                if self.matching('>') {
+                    return self.make_token(TokenType::TokenPipe);
+                }
+                self.error_token("Unexpected Character.")
+            }
+            '"' => return self.string(false),
+            ':' => {
+                // A symbol, like ':ready'
+                let mut peek = self.peek();
+                if self.is_alpha(peek) {
+                    while self.is_identifier_char(peek) { self.advance(); peek = self.peek(); };
+                    return self.make_token(TokenType::TokenSymbol);
+                }
+                self.error_token("Unexpected Character.")
+            }
             
             _ => return {
                 //print!("-> {}", curr_char);
@@ -126,6 +174,69 @@ impl Scanner {
         }
     }
 
+    // Returns the '///' comments written right before the last scanned token, joined into one text
+    pub fn take_doc_comment(&mut self) -> Option<String> {
+        if self.doc_comment.is_empty() { return None; }
+        Some(std::mem::take(&mut self.doc_comment).join("\n"))
+    }
+
+    /*
+    Scans the symbol of an operator being declared, like the '<+>' in 'infix 6 <+> (a, b) { ... }'
+    It has to be scanned separately, since the symbol isn't an operator yet and would be split up into other tokens
+    */
+    pub fn operator_symbol(&mut self) -> Token {
+        if let Some(error) = self.skip_white_space() { return error; }
+        self.start = self.current;
+        while Scanner::is_operator_char(self.peek()) {
+            // The start of a comment ends the symbol
+            if self.peek() == '/' && (self.peek_next() == '/' || self.peek_next() == '*') { break; }
+            self.advance();
+        }
+        if self.start == self.current {
+            return self.error_token("Expect operator symbol.");
+        }
+        self.make_token(TokenType::TokenOperator)
+    }
+
+    // Makes the scanner scan a declared operator as one token
+    pub fn add_operator(&mut self, symbol: &str) {
+        self.operators.push(symbol.to_string());
+        self.operators.sort_by_key(|symbol| std::cmp::Reverse(symbol.len()));
+    }
+
+    // Whether a symbol is already scanned as a single built-in token, like '+=' or '|>'
+    pub fn is_built_in_operator(symbol: &str) -> bool {
+        let mut scanner = Scanner::new();
+        scanner.source = symbol.to_string();
+        let token = scanner.scan_token();
+        token._type != TokenType::TokenError && token.lexeme == symbol
+    }
+
+    // Scans a symbol into the built-in tokens it is made of, like '-' and '-' for '--'
+    pub fn built_in_tokens(symbol: &str) -> Vec<Token> {
+        let mut scanner = Scanner::new();
+        scanner.source = symbol.to_string();
+        let mut tokens: Vec<Token> = Vec::new();
+        loop {
+            let token = scanner.scan_token();
+            if token._type == TokenType::TokenEOF { return tokens; }
+            tokens.push(token);
+        }
+    }
+
+    fn is_operator_char(c: char) -> bool {
+        matches!(c, '+' | '-' | '*' | '/' | '%' | '^' | '<' | '>' | '=' | '!' | '&' | '|' | '~' | '?')
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    // Returns the source text between two positions, used to quote code back in messages
+    pub fn source_text(&self, begin: usize, end: usize) -> String {
+        self.source.substring(begin, end)
+    }
+
     // If the current character is the desired one, we advance and return true. Otherwise, we return false to indicate it wasn’t matched. 
     fn matching(&mut self, expected : char) -> bool {
         if self.is_at_end() { return false; }
@@ -133,15 +244,16 @@ impl Scanner {
         // If the char at the current index position is not the same as the char expected, return false
         if self.source.char_at(curr_char_index) != expected { return false; }
         else {
-            self.current += 1;
+            self.current += expected.len_utf8();
             return true;
         }
     }
 
     // consumes the current character and returns it
+    // Positions are byte offsets into the source, so we move past every byte of the character
     fn advance(&mut self) -> char {
         let curr_source_char: char = self.source.char_at(self.current);
-        self.current += 1;
+        self.current += curr_source_char.len_utf8();
 
         curr_source_char
     }
@@ -158,17 +270,23 @@ impl Scanner {
 
     // This is like peek() but for one character past the current one
     fn peek_next(&mut self) -> char {
-        if self.current + 1 >= self.source.len() { return '\0'; }
+        let curr_char_len = self.peek().len_utf8();
 
-        self.source.char_at(self.current + 1)
+        self.source.char_at(self.current + curr_char_len)
     }
 
     fn is_digit(&self, c : char) -> bool {
-        (c >= '0') && (c <= '9')
+        c.is_ascii_digit()
     }
 
+    // Identifiers start with a letter or '_', which includes any unicode XID_Start character
     fn is_alpha(&self, c : char) -> bool {
-        (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || (c == '_')
+        c == '_' || unicode_ident::is_xid_start(c)
+    }
+
+    // After the first character, identifiers can also use any unicode XID_Continue character, like digits
+    fn is_identifier_char(&self, c : char) -> bool {
+        unicode_ident::is_xid_continue(c)
     }
 
     /*
@@ -176,7 +294,7 @@ impl Scanner {
     */
     fn identifier(&mut self) -> Token {
         let mut peek = self.peek();
-        while self.is_alpha(peek) || self.is_digit(peek) { self.advance(); peek = self.peek(); };
+        while self.is_identifier_char(peek) { self.advance(); peek = self.peek(); };
         let identify_type: TokenType = self.identifier_type();
         
         self.make_token(identify_type)
@@ -187,7 +305,15 @@ impl Scanner {
     */
     fn identifier_type(&mut self) -> TokenType {
         match self.source.char_at(self.start){
-            'a' => return self.check_keyword(1,2,"nd", TokenType::TokenAnd),
+            'a' => {
+                if self.current - self.start > 1{
+                    match self.source.char_at(self.start + 1){
+                        'n' => return self.check_keyword(2, 1, "d", TokenType::TokenAnd),
+                        's' => return self.check_keyword(2, 4, "sert", TokenType::TokenAssert),
+                        _ => return TokenType::TokenIdentifier
+                    }
+                }
+            },
             'c' => {
                 if self.current - self.start > 1{
                     match self.source.char_at(self.start + 1){
@@ -197,6 +323,7 @@ impl Scanner {
                     }
                 }
             },
+            'd' => return self.check_keyword(1, 4, "efer", TokenType::TokenDefer),
             'e' => return self.check_keyword(1, 3, "lse", TokenType::TokenElse),
             'f' => {
                 if self.current - self.start > 1{
@@ -212,7 +339,15 @@ impl Scanner {
             'n' => return self.check_keyword(1, 2, "il", TokenType::TokenNil),
             'o' => return self.check_keyword(1, 1, "r", TokenType::TokenOr),
             'p' => return self.check_keyword(1, 4, "rint", TokenType::TokenPrint),
-            'r' => return self.check_keyword(1, 5, "eturn", TokenType::TokenReturn),
+            'r' => {
+                if self.current - self.start > 2 && self.source.char_at(self.start + 1) == 'e' {
+                    match self.source.char_at(self.start + 2){
+                        'c' => return self.check_keyword(3, 3, "ord", TokenType::TokenRecord),
+                        't' => return self.check_keyword(3, 3, "urn", TokenType::TokenReturn),
+                        _ => return TokenType::TokenIdentifier
+                    }
+                }
+            },
             's' => return self.check_keyword(1, 4, "uper", TokenType::TokenSuper),
             't' => {
                 if self.current - self.start > 1 {
@@ -257,10 +392,21 @@ impl Scanner {
         TokenType::TokenIdentifier
     }
 
-    // Gets the token for a number
+    /*
+    Gets the token for a number
+    Besides decimals, this accepts 0x, 0b and 0o prefixed literals, '_' separators and exponents.
+    The compiler checks that the digits make sense when it converts the lexeme
+    */
     fn number(&mut self) -> Token {
         let mut peek: char = self.peek();
-        while self.is_digit(peek) {self.advance(); peek = self.peek();}
+        if self.source.char_at(self.start) == '0' && matches!(peek, 'x' | 'X' | 'b' | 'B' | 'o' | 'O') {
+            self.advance();
+            peek = self.peek();
+            while peek.is_ascii_alphanumeric() || peek == '_' {self.advance(); peek = self.peek();}
+            return self.make_token(TokenType::TokenNumber);
+        }
+
+        while self.is_digit(peek) || peek == '_' {self.advance(); peek = self.peek();}
             // Look for fractional part
             let peek_next = self.peek_next();
             if self.peek() == '.' && self.is_digit(peek_next){
@@ -268,15 +414,43 @@ impl Scanner {
                 self.advance();
 
                 peek = self.peek();
-                while self.is_digit(peek) {self.advance(); peek = self.peek();}
+                while self.is_digit(peek) || peek == '_' {self.advance(); peek = self.peek();}
+            }
+
+            // Look for an exponent, which can have a sign
+            let peek_next = self.peek_next();
+            let has_sign = peek_next == '+' || peek_next == '-';
+            let after_sign = if has_sign { self.source.char_at(self.current + 2) } else { peek_next };
+            if (self.peek() == 'e' || self.peek() == 'E') && self.is_digit(after_sign) {
+                // consume the "e" and the sign
+                self.advance();
+                if has_sign {self.advance();}
+
+                peek = self.peek();
+                while self.is_digit(peek) || peek == '_' {self.advance(); peek = self.peek();}
             }
 
         self.make_token(TokenType::TokenNumber)
     }
 
     // Gets the token for a string
-    fn string(&mut self) -> Token {
-        while self.peek() != '"' && !self.is_at_end() {
+    // The opening quote has been consumed, along with the 'r' of a raw string
+    // Triple-quoted strings end at the next three quotes, so they can contain single quotes
+    fn string(&mut self, raw: bool) -> Token {
+        let start_line = self.line;
+        let triple = self.source[self.current..].starts_with("\"\"");
+        if triple {
+            self.advance();
+            self.advance();
+        }
+        let quotes = if triple { "\"\"\"" } else { "\"" };
+        let body_start = self.current;
+
+        while !self.source[self.current..].starts_with(quotes) && !self.is_at_end() {
+            // Skip over the escaped character, so an escaped quote doesn't end the string
+            if !raw && self.peek() == '\\' && self.peek_next() != '\0' {
+                self.advance();
+            }
             if self.peek() == '\n'{self.line += 1;}
             self.advance();
         }
@@ -284,13 +458,23 @@ impl Scanner {
         if self.is_at_end() { 
             return self.error_token("Unterminated String."); 
         }
-        self.advance(); // The closing quote
+        let body_end = self.current;
+        self.current += quotes.len(); // The closing quotes
+
+        // Check the escape sequences here, so an invalid one is reported on the line it is on
+        let body = self.source.substring(body_start, body_end);
+        if let (false, Err((index, message))) = (raw, unescape(&body)) {
+            let line = start_line + body[..index].matches('\n').count();
+            return self.error_token_at(message, line);
+        }
 
         self.make_token(TokenType::TokenString)
     }   
 
     // This advances the scanner past any leading whitespace and also ignores all comments
-    fn skip_white_space(&mut self) {
+    // Returns an error token if a block comment is never closed
+    fn skip_white_space(&mut self) -> Option<Token> {
+        self.doc_comment.clear();
         loop {
             let curr_char : char = self.peek();
             match curr_char {
@@ -304,35 +488,184 @@ impl Scanner {
                 '/' => {
                     if self.peek_next() == '/' {
                         // A comment goes until the end of the line
+                        let comment_start = self.current;
                         while self.peek() != '\n' && !self.is_at_end() {
                             self.advance();
                         }
+                        // Comments starting with exactly three slashes document the declaration after them
+                        let comment = self.source.substring(comment_start, self.current);
+                        if let Some(doc) = comment.strip_prefix("///").filter(|doc| !doc.starts_with('/')) {
+                            self.doc_comment.push(doc.strip_prefix(' ').unwrap_or(doc).trim_end().to_string());
+                        }
+                    } else if self.peek_next() == '*' {
+                        if let Some(error) = self.block_comment() { return Some(error); }
                     } else {
-                        return
+                        return None
                     }
                 }
-                _ => return
+                _ => return None
             }
         }
     }
 
+    // Skips a block comment, which can have other block comments nested inside of it
+    fn block_comment(&mut self) -> Option<Token> {
+        // consume the opening "/*"
+        self.advance();
+        self.advance();
+
+        let mut depth: usize = 1;
+        while depth > 0 {
+            if self.is_at_end() {
+                return Some(self.error_token("Unterminated block comment."));
+            }
+            let curr_char: char = self.advance();
+            if curr_char == '\n' {
+                self.line += 1;
+            } else if curr_char == '/' && self.peek() == '*' {
+                self.advance();
+                depth += 1;
+            } else if curr_char == '*' && self.peek() == '/' {
+                self.advance();
+                depth -= 1;
+            }
+        }
+        None
+    }
+
     // Makes the token, with the corresponding data
     fn make_token(&self, _type: TokenType) -> Token {
         Token { 
             _type: _type,
             lexeme: self.source.substring(self.start, self.current), 
-            line: self.line 
+            line: self.line,
+            newline_before: self.newline_before,
+            expansion: 0,
+            offset: Some(self.start),
         }
     }
 
     // Makes error token, with the corresponding data
     fn error_token(&self, message: &str) -> Token {
+        self.error_token_at(message, self.line)
+    }
+
+    // Makes error token for a line other than the current one
+    fn error_token_at(&self, message: &str, line: usize) -> Token {
         Token {
             _type: TokenType::TokenError,
             lexeme: message.to_string(),
-            line: self.line
+            line,
+            newline_before: self.newline_before,
+            expansion: 0,
+            offset: Some(self.start),
+        }
+    }
+}
+
+/*
+Returns the value of a string literal from its lexeme, which is one of
+    "..."           with escape sequences
+    r"..."          a raw string, where a backslash is just a backslash
+    """..."""       which can span lines and contain quotes, with the indentation its lines share removed
+    r"""..."""      both raw and triple-quoted
+*/
+pub fn string_value(lexeme: &str) -> Result<String, (usize, &'static str)> {
+    let raw = lexeme.starts_with('r');
+    let quoted = if raw { &lexeme[1..] } else { lexeme };
+    let triple = quoted.len() >= 6 && quoted.starts_with("\"\"\"");
+    let quotes = if triple { 3 } else { 1 };
+    let body = &quoted[quotes..quoted.len() - quotes];
+
+    let body = if triple { dedent(body) } else { body.to_string() };
+    if raw {
+        return Ok(body);
+    }
+    unescape(&body)
+}
+
+/*
+Removes the indentation that the lines of a triple-quoted string share. A line break right after the opening quotes
+and a last line with only the indentation of the closing quotes are left out too
+*/
+fn dedent(body: &str) -> String {
+    let mut lines: Vec<&str> = body.split('\n').collect();
+    if lines.len() == 1 {
+        return body.to_string();
+    }
+    if lines[0].trim().is_empty() {
+        lines.remove(0);
+    }
+    if lines.len() > 1 && lines[lines.len() - 1].trim().is_empty() {
+        lines.pop();
+    }
+
+    let is_indent = |c: char| c == ' ' || c == '\t';
+    let indent = lines.iter()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start_matches(is_indent).len())
+        .min()
+        .unwrap_or(0);
+    let lines: Vec<&str> = lines.iter()
+        .map(|line| if line.trim().is_empty() { "" } else { &line[indent..] })
+        .collect();
+    lines.join("\n")
+}
+
+/*
+Replaces the escape sequences in the body of a string literal (the text between the quotes)
+with the characters they stand for.
+On an invalid escape sequence, returns its byte index in the body along with the error message
+*/
+pub fn unescape(body: &str) -> Result<String, (usize, &'static str)> {
+    let mut result = String::new();
+    let mut chars = body.char_indices();
+
+    while let Some((index, curr_char)) = chars.next() {
+        if curr_char != '\\' {
+            result.push(curr_char);
+            continue;
         }
+
+        let escaped: char = match chars.next().map(|(_, c)| c) {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('\\') => '\\',
+            Some('"') => '"',
+            Some('0') => '\0',
+            // \xNN, exactly two hex digits
+            Some('x') => {
+                let digits: String = chars.by_ref().take(2).map(|(_, c)| c).collect();
+                if digits.len() != 2 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err((index, "Expect two hex digits after '\\x'."));
+                }
+                char::from(u8::from_str_radix(&digits, 16).unwrap())
+            }
+            // \u{XXXX}, one to six hex digits naming a unicode scalar value
+            Some('u') => {
+                if chars.next().map(|(_, c)| c) != Some('{') {
+                    return Err((index, "Expect '{' after '\\u'."));
+                }
+                let mut digits = String::new();
+                loop {
+                    match chars.next().map(|(_, c)| c) {
+                        Some('}') => break,
+                        Some(c) if c.is_ascii_hexdigit() && digits.len() < 6 => digits.push(c),
+                        _ => return Err((index, "Expect 1 to 6 hex digits and '}' in '\\u{...}'."))
+                    }
+                }
+                match u32::from_str_radix(&digits, 16).ok().and_then(char::from_u32) {
+                    Some(c) => c,
+                    None => return Err((index, "Invalid unicode escape sequence."))
+                }
+            }
+            _ => return Err((index, "Invalid escape sequence."))
+        };
+        result.push(escaped);
     }
+
+    Ok(result)
 }
 
 /*
@@ -346,28 +679,21 @@ trait StringUtils {
 
 impl StringUtils for String {
     /* 
-    Returns a substring, which begins at the specified begin_index and extends
-    to the character at index end_index - 1
+    Returns a substring, which begins at the byte offset begin_index and extends
+    to the byte at offset end_index - 1
     */
     fn substring(&self, begin_index: usize, end_index: usize) -> Self {
-        if begin_index + (end_index - begin_index) > self.len() {
-            panic!("substring(): index out of bounds");
+        match self.get(begin_index..end_index) {
+            Some(slice) => slice.to_string(),
+            None => panic!("substring(): index out of bounds")
         }
-        self.chars().skip(begin_index).take(end_index - begin_index).collect()
     }
 
     /*
-    Gets the character in index position
+    Gets the character that starts at the byte offset index_pos.
+    Past the end of the source there is nothing left, which reads as '\0'
     */
     fn char_at(&mut self, index_pos: usize) -> char {
-        let curr_source_char : char =  match self.chars().nth(index_pos) {
-            Some(x) => x,
-            None => {
-                println!("advance(): char not accessible by index. Returning empty space. Index: {}", index_pos);
-                return ' '
-            }
-        };
-
-        curr_source_char
+        self.get(index_pos..).and_then(|rest| rest.chars().next()).unwrap_or('\0')
     }
 }
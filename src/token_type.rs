@@ -6,7 +6,7 @@ pub enum TokenType{
     // Single-character tokens
     TokenLeftParen, TokenRightParen,
     TokenLeftBrace, TokenRightBrace,
-    TokenComma, TokenDot, TokenSemicolon, 
+    TokenComma, TokenDot, TokenSemicolon, TokenAt,
 
     // One or two character tokens
     TokenBang, TokenBangEqual,
@@ -19,15 +19,19 @@ pub enum TokenType{
     TokenStar, TokenStarEqual,
     TokenCarat, TokenCaratEqual,
     TokenPercent, TokenPercentEqual,
+    TokenPipe,
+    // An operator declared with 'infix' or 'infixr'
+    TokenOperator,
 
     // Literals
-    TokenIdentifier, TokenString, TokenNumber,
+    TokenIdentifier, TokenString, TokenNumber, TokenSymbol,
 
     // Keywords
     TokenAnd, TokenClass, TokenElse, TokenFalse, 
     TokenFor, TokenFun, TokenIf, TokenNil, TokenOr, 
     TokenPrint, TokenReturn, TokenSuper, TokenThis,
     TokenTrue, TokenVar, TokenWhile, TokenConst,
+    TokenAssert, TokenDefer, TokenRecord,
 
     // Miscellaneous
     TokenError, TokenEOF, Undefined
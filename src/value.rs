@@ -1,36 +1,180 @@
 use core::panic;
-use std::{fmt::{Display, Formatter, Error},  rc::Rc, any::Any};
+use std::{fmt::{Display, Formatter, Error},  sync::Arc};
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Condvar, Mutex, OnceLock};
+use std::thread::JoinHandle;
 use crate::chunk::*;
+use crate::persistent::{Map, Vector};
+use crate::vm::VM;
 use core::fmt::Debug;
 use std::cmp::Ordering;
+use std::hash::{Hash, Hasher};
 
 pub type Number = f64;
 
-pub trait NativeFn{
-    fn fun_call(&self, arg_count: usize, args: &[Value]) -> Value;
+// Natives are shared by the VMs of every thread
+pub trait NativeFn: Send + Sync{
+    // The VM is passed along for natives that need to look at its state, like its globals
+    fn fun_call(&self, vm: &mut VM, arg_count: usize, args: &[Value]) -> Result<Value, NativeError>;
 }
 
+// How a native call failed
+#[derive(Debug)]
+pub enum NativeError {
+    // An error for the VM to report
+    Message(String),
+    // An error that was already reported, by a function the native called
+    Reported,
+}
+
+impl From<String> for NativeError {
+    fn from(message: String) -> Self {
+        NativeError::Message(message)
+    }
+}
+
+impl From<&str> for NativeError {
+    fn from(message: &str) -> Self {
+        NativeError::Message(message.to_string())
+    }
+}
+
+// A native function, with what the VM knows about it
+#[derive(Clone, Debug)]
+pub struct Native{
+    pub name: String,
+    // None if the native takes any number of arguments
+    pub arity: Option<usize>,
+    pub doc: Option<String>,
+    pub function: Arc<dyn NativeFn>,
+}
+
+// A method of a built-in type, called with the receiver and the arguments
+pub type NativeMethod = fn(&Value, &[Value]) -> Result<Value, String>;
+
 #[derive(Debug, Clone)]
 pub enum Value{
     Bool(bool),
     Number(Number),
     String(String),
-    Fun(Function),
-    Native(Rc<dyn NativeFn>),
+    Fun(Arc<Function>),
+    Native(Native),
+    RecordType(Arc<RecordType>),
+    Record(Arc<Record>),
+    Channel(Arc<Channel>),
+    Worker(Arc<Worker>),
+    Symbol(Symbol),
+    Vector(Vector),
+    Map(Map),
     Nil
 }
 
+// A symbol, like ':ready'. Symbols are interned, so two symbols with the same name share their string
+// and are compared by pointer instead of character by character
+#[derive(Clone, Debug)]
+pub struct Symbol(Arc<str>);
+
+impl Symbol{
+    // Returns the symbol with the given name, creating it the first time the name is used
+    // Every thread uses the same symbols, so they can be sent between threads as they are
+    pub fn intern(name: &str) -> Self {
+        static SYMBOLS: OnceLock<Mutex<HashSet<Arc<str>>>> = OnceLock::new();
+        let mut symbols = SYMBOLS.get_or_init(|| Mutex::new(HashSet::new())).lock().unwrap();
+        if let Some(symbol) = symbols.get(name) {
+            return Symbol(symbol.clone());
+        }
+        let symbol: Arc<str> = Arc::from(name);
+        symbols.insert(symbol.clone());
+        Symbol(symbol)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+}
+
+// The type of a record, created by a record declaration like 'record Point(x, y);'
+#[derive(PartialEq, Debug)]
+pub struct RecordType{
+    pub name: String,
+    pub fields: Vec<String>,
+}
+
+// An immutable instance of a record type, with a value for each of its fields
+#[derive(PartialEq, Debug)]
+pub struct Record{
+    pub record_type: Arc<RecordType>,
+    pub values: Vec<Value>,
+}
+
+impl Record{
+    // Returns the value of the field with the given name
+    pub fn get(&self, field: &str) -> Option<&Value> {
+        let index = self.record_type.fields.iter().position(|name| name == field)?;
+        self.values.get(index)
+    }
+}
+
 #[derive(PartialEq, Clone, Debug)]
 pub struct Function{
     pub arity: usize,
     pub chunk: Chunk,
     pub name: Option<String>,
+    // Taken from a docstring or from '///' comments before the declaration
+    pub doc: Option<String>,
 }
 
 
 impl Function{
     pub fn new(arity: usize, chunk: Chunk, name: Option<String>) -> Self{
-        Function { arity: arity, chunk: chunk, name: name}
+        Function { arity: arity, chunk: chunk, name: name, doc: None}
+    }
+}
+
+// A queue of values that VMs on different threads use to pass messages to each other
+#[derive(Debug, Default)]
+pub struct Channel{
+    queue: Mutex<VecDeque<Value>>,
+    ready: Condvar,
+}
+
+impl Channel{
+    pub fn send(&self, value: Value) {
+        self.queue.lock().unwrap().push_back(value);
+        self.ready.notify_one();
+    }
+
+    // Waits until there is a value to receive
+    pub fn recv(&self) -> Value {
+        let mut queue = self.queue.lock().unwrap();
+        while queue.is_empty() {
+            queue = self.ready.wait(queue).unwrap();
+        }
+        queue.pop_front().unwrap()
+    }
+}
+
+// A function running in its own VM on another thread, created by spawn()
+#[derive(Debug)]
+pub struct Worker{
+    handle: Mutex<Option<JoinHandle<Result<Value, String>>>>,
+    result: Mutex<Option<Result<Value, String>>>,
+}
+
+impl Worker{
+    pub fn new(handle: JoinHandle<Result<Value, String>>) -> Self {
+        Worker { handle: Mutex::new(Some(handle)), result: Mutex::new(None) }
+    }
+
+    // Waits for the thread to finish and returns what the function returned
+    // The result is kept, so a worker can be joined more than once
+    pub fn join(&self) -> Result<Value, String> {
+        let mut handle = self.handle.lock().unwrap();
+        if let Some(handle) = handle.take() {
+            let result = handle.join().unwrap_or(Err("Worker thread panicked.".to_string()));
+            *self.result.lock().unwrap() = Some(result);
+        }
+        self.result.lock().unwrap().clone().unwrap()
     }
 }
 
@@ -58,7 +202,7 @@ impl From<String> for Value{
 // Convert Function to Value::Fun(Function)
 impl From<Function> for Value{
     fn from(_function: Function) -> Self {
-        Value::Fun(_function)
+        Value::Fun(Arc::new(_function))
     }
 }
 
@@ -95,7 +239,25 @@ impl From<Value> for String{
 impl From<Value> for Function{
     fn from(_value: Value) -> Self {
         match _value {
-            Value::Fun(_function) => _function,
+            Value::Fun(_function) => _function.as_ref().clone(),
+            _ => panic!()
+        }
+    }
+}
+
+impl From<Value> for Vector{
+    fn from(_value: Value) -> Self {
+        match _value {
+            Value::Vector(_vector) => _vector,
+            _ => panic!()
+        }
+    }
+}
+
+impl From<Value> for Map{
+    fn from(_value: Value) -> Self {
+        match _value {
+            Value::Map(_map) => _map,
             _ => panic!()
         }
     }
@@ -115,6 +277,28 @@ impl Display for Value{
                 }
             },
             Value::Native(_native_fun) => "<native fn>".to_string(),
+            Value::RecordType(_record_type) => format!("<record {}>", _record_type.name),
+            Value::Record(_record) => {
+                let fields: Vec<String> = _record.record_type.fields.iter().zip(_record.values.iter())
+                    .map(|(name, value)| format!("{}: {}", name, value))
+                    .collect();
+                format!("{}({})", _record.record_type.name, fields.join(", "))
+            },
+            Value::Channel(_) => "<channel>".to_string(),
+            Value::Worker(_) => "<worker>".to_string(),
+            Value::Symbol(_symbol) => format!(":{}", _symbol.name()),
+            Value::Vector(_vector) => {
+                let values: Vec<String> = _vector.iter().map(|value| value.to_string()).collect();
+                format!("[{}]", values.join(", "))
+            },
+            // Sorted, since the order of the pairs in the map depends on the hashes of the keys
+            Value::Map(_map) => {
+                let mut pairs: Vec<String> = _map.pairs().iter()
+                    .map(|(key, value)| format!("{}: {}", key, value))
+                    .collect();
+                pairs.sort();
+                format!("{{{}}}", pairs.join(", "))
+            },
         })
     }
 }
@@ -138,12 +322,49 @@ impl PartialEq for Value{
             (Value::String(a), Value::String(b)) => a.cmp(b) == Ordering::Equal,
             (Value::Nil, Value::Nil) => true,
             (Value::Fun(a), Value::Fun(b)) => a == b,
-            (Value::Native(a), Value::Native(b)) => a.type_id() == b.type_id(),
+            (Value::Native(a), Value::Native(b)) => a.name == b.name,
+            (Value::RecordType(a), Value::RecordType(b)) => a == b,
+            // Records are compared by value, field by field
+            (Value::Record(a), Value::Record(b)) => a == b,
+            (Value::Channel(a), Value::Channel(b)) => Arc::ptr_eq(a, b),
+            (Value::Worker(a), Value::Worker(b)) => Arc::ptr_eq(a, b),
+            (Value::Symbol(a), Value::Symbol(b)) => Arc::ptr_eq(&a.0, &b.0),
+            // Vectors and maps are compared by value, like records
+            (Value::Vector(a), Value::Vector(b)) => a == b,
+            (Value::Map(a), Value::Map(b)) => a == b,
             _ => false
         }
     }
 }
 
+// Values can be used as keys of a hash map. Equal values must hash the same
+impl Eq for Value {}
+
+impl Hash for Value{
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Value::Bool(_bool) => _bool.hash(state),
+            // 0 and -0 are equal, so they share a hash
+            Value::Number(_number) => (if *_number == 0.0 { 0.0 } else { *_number }).to_bits().hash(state),
+            Value::String(_string) => _string.hash(state),
+            Value::Fun(_function) => _function.name.hash(state),
+            Value::Native(_native) => _native.name.hash(state),
+            Value::RecordType(_record_type) => _record_type.name.hash(state),
+            Value::Record(_record) => {
+                _record.record_type.name.hash(state);
+                _record.values.hash(state);
+            },
+            Value::Channel(_channel) => Arc::as_ptr(_channel).hash(state),
+            Value::Worker(_worker) => Arc::as_ptr(_worker).hash(state),
+            Value::Symbol(_symbol) => _symbol.0.as_ptr().hash(state),
+            Value::Vector(_vector) => _vector.hash(state),
+            Value::Map(_map) => _map.hash(state),
+            Value::Nil => (),
+        }
+    }
+}
+
 impl Debug for dyn NativeFn{
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(f, "<native fn>")
@@ -185,6 +406,51 @@ pub fn is_bool(_value: Value) -> bool {
 }
 
 impl Value{    
+    // Name of the type of the value, as shown to the user
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Bool(_) => "bool",
+            Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Fun(_) => "function",
+            Value::Native(_) => "native",
+            Value::RecordType(_) => "record type",
+            Value::Record(_) => "record",
+            Value::Channel(_) => "channel",
+            Value::Worker(_) => "worker",
+            Value::Symbol(_) => "symbol",
+            Value::Vector(_) => "vector",
+            Value::Map(_) => "map",
+            Value::Nil => "nil",
+        }
+    }
+
+    // Copies the value so it can be given to a VM on another thread
+    // Data is deep copied, functions, record types and symbols are shared since they are read-only,
+    // and a channel stays the same channel so both threads can use it
+    pub fn to_sendable(&self) -> Result<Value, String> {
+        match self {
+            Value::Bool(_) | Value::Number(_) | Value::String(_) | Value::Symbol(_) | Value::Nil => Ok(self.clone()),
+            Value::Fun(_) | Value::RecordType(_) | Value::Channel(_) => Ok(self.clone()),
+            Value::Record(_record) => {
+                let values = _record.values.iter().map(|value| value.to_sendable()).collect::<Result<Vec<Value>, String>>()?;
+                Ok(Value::Record(Arc::new(Record { record_type: _record.record_type.clone(), values })))
+            },
+            Value::Vector(_vector) => {
+                let values = _vector.iter().map(|value| value.to_sendable()).collect::<Result<Vec<Value>, String>>()?;
+                Ok(Value::Vector(Vector::from_values(&values)))
+            },
+            Value::Map(_map) => {
+                let mut map = Map::new();
+                for (key, value) in _map.pairs() {
+                    map = map.insert(key.to_sendable()?, value.to_sendable()?);
+                }
+                Ok(Value::Map(map))
+            },
+            Value::Native(_) | Value::Worker(_) => Err(format!("Can't send a {} to another thread.", self.type_name()))
+        }
+    }
+
     // If the Value is False or Nil return true (they are false), else return false (they are true)
     pub fn is_falsey(&self) -> bool{
         match self{
@@ -1,34 +1,55 @@
 use std::cell::RefCell;
 use std::collections::HashMap;
-use std::rc::Rc;
-use std::time::SystemTime;
+use std::sync::Arc;
 use std::borrow::Borrow;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::chunk::*;
 use crate::value::*;
 use crate::compiler::*;
 use crate::debug::*;
+use crate::methods::*;
+use crate::natives::*;
 
 
 pub struct VM {
     frames: Vec<CallFrame>,
     stack : Vec<Value>,
     compiler : Compiler,
-    globals : HashMap<String, Value>,
+    globals : HashMap<String, Global>,
+    // Methods of the built-in types, by type name and then by method name
+    methods : HashMap<&'static str, HashMap<&'static str, NativeMethod>>,
+    // Callbacks scheduled by set_timeout and set_interval, run by the event loop after the script
+    timers : Vec<Timer>,
+    next_timer_id : usize,
+    start : Instant,
+    // Some when the VM uses a virtual clock, which jumps straight to the next timer instead of sleeping
+    virtual_time : Option<f64>,
+    // Threads started by spawn(), which are joined before the VM finishes
+    workers : Vec<Arc<Worker>>,
+    // In a worker, the globals spawn() couldn't copy to it, along with the reason
+    unsent_globals : HashMap<String, String>,
 }
 
-pub struct NativeClock{}
+/*
+    A global variable, along with whether it was declared const
+*/
+pub struct Global {
+    pub value: Value,
+    pub constant: bool,
+}
 
-// Implements NativeFn trait for NativeClock
-impl NativeFn for NativeClock{
-    // CITE: UncleScientist lox-bytecode repo in Github
-    // CITE: https://github.com/UncleScientist/lox-bytecode
-    fn fun_call(&self, _arg_count: usize, _args: &[Value]) -> Value {
-        match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH){
-            Ok(time) => Value::Number(time.as_millis() as f64),
-            Err(_) => panic!("Can't get system time")
-        }
-    }
+/*
+    A callback scheduled by set_timeout or set_interval
+*/
+pub struct Timer {
+    id: usize,
+    // When the callback runs next, in milliseconds since the VM started
+    due: f64,
+    // Some if the timer repeats
+    interval: Option<f64>,
+    callback: Value,
 }
 
 #[derive(Debug,PartialEq)]
@@ -39,9 +60,11 @@ pub enum InterpretResult {
 }
 
 pub struct CallFrame{
-    function: Function,
+    function: Arc<Function>,
     ip: RefCell<usize>,
-    slots: usize
+    slots: usize,
+    // The defer statements of the function that ran and whose code hasn't run yet, by their number
+    deferred: RefCell<Vec<u8>>,
 } 
 
 // CITE: Learned to modify a RefCell object by a method by UncleScientist lox-bytecode repo in Github
@@ -63,9 +86,50 @@ impl VM {
             stack : Vec::new(),
             compiler : Compiler::new(),
             globals : HashMap::new(),
+            methods : HashMap::new(),
+            timers : Vec::new(),
+            next_timer_id : 1,
+            start : Instant::now(),
+            virtual_time : None,
+            workers : Vec::new(),
+            unsent_globals : HashMap::new(),
         };
-        let native_fun : Rc<dyn NativeFn> = Rc::new(NativeClock{});
-        vm.define_native("clock".to_string(), &native_fun);
+        vm.define_native("clock", Some(0), "Returns the time since the Unix epoch in milliseconds.", Arc::new(NativeClock{}));
+        vm.define_native("help", Some(1), "Prints the name, arity and documentation of a function.", Arc::new(NativeHelp{}));
+        vm.define_native("type_of", Some(1), "Returns the name of the type of a value.", Arc::new(NativeTypeOf{}));
+        vm.define_native("arity", Some(1), "Returns the number of parameters of a function.", Arc::new(NativeArity{}));
+        vm.define_native("name_of", Some(1), "Returns the name of a function.", Arc::new(NativeNameOf{}));
+        vm.define_native("globals", Some(0), "Returns every defined global and its value, one per line.", Arc::new(NativeGlobals{}));
+        vm.define_native("defined", Some(1), "Returns whether a global with the given name is defined.", Arc::new(NativeDefined{}));
+        vm.define_native("set_timeout", Some(2), "Calls a function once, after a delay in milliseconds. Returns the id of the timer.", Arc::new(NativeSetTimeout{}));
+        vm.define_native("set_interval", Some(2), "Calls a function repeatedly, every interval in milliseconds. Returns the id of the timer.", Arc::new(NativeSetInterval{}));
+        vm.define_native("cancel", Some(1), "Cancels the timer with the given id. Returns whether the timer was still scheduled.", Arc::new(NativeCancel{}));
+        vm.define_native("spawn", None, "Calls a function with the given arguments in a new VM on another thread. Returns a worker.", Arc::new(NativeSpawn{}));
+        vm.define_native("join", Some(1), "Waits for a worker to finish and returns what its function returned.", Arc::new(NativeJoin{}));
+        vm.define_native("channel", Some(0), "Returns a new channel, which threads use to send values to each other.", Arc::new(NativeChannel{}));
+        vm.define_native("send", Some(2), "Sends a value through a channel.", Arc::new(NativeSend{}));
+        vm.define_native("memoize", Some(1), "Returns a native that calls a function once for each set of arguments and remembers the results.", Arc::new(NativeMemoize{}));
+        vm.define_native("recv", Some(1), "Waits for a value from a channel and returns it.", Arc::new(NativeRecv{}));
+        vm.define_native("symbol", Some(1), "Returns the symbol with the given name.", Arc::new(NativeSymbol{}));
+        vm.define_native("symbol_name", Some(1), "Returns the name of a symbol as a string.", Arc::new(NativeSymbolName{}));
+        vm.define_native("vector", None, "Creates a persistent vector of the arguments.", Arc::new(NativeVector{}));
+        vm.define_native("hash_map", None, "Creates a persistent map from keys each followed by their value.", Arc::new(NativeHashMap{}));
+        vm.define_native("conj", Some(2), "Returns a new vector with the value added at the end.", Arc::new(NativeConj{}));
+        vm.define_native("assoc", Some(3), "Returns a new map with the key set to the value, or a new vector with the value at the index.", Arc::new(NativeAssoc{}));
+        vm.define_native("dissoc", Some(2), "Returns a new map without the key.", Arc::new(NativeDissoc{}));
+        vm.define_native("get", Some(2), "Returns the value of a key in a map or at an index in a vector, or nil if there is none.", Arc::new(NativeGet{}));
+        for (name, method) in string_methods() {
+            vm.define_method("string", name, method);
+        }
+        for (name, method) in number_methods() {
+            vm.define_method("number", name, method);
+        }
+        for (name, method) in vector_methods() {
+            vm.define_method("vector", name, method);
+        }
+        for (name, method) in map_methods() {
+            vm.define_method("map", name, method);
+        }
         vm
     }
 
@@ -167,9 +231,9 @@ impl VM {
     }
 
     /**
-     * Runs the bytecode given by the compiler
+     * Runs the bytecode given by the compiler, until only base_frames call frames are left
      */
-    fn run(&mut self) -> InterpretResult {
+    fn run(&mut self, base_frames: usize) -> InterpretResult {
 
         // let mut frame= self.curr_frame();
 
@@ -205,6 +269,14 @@ impl VM {
                 OpCode::OpPop => {
                     self.pop();
                 },
+                OpCode::OpSwap => {
+                    let len = self.stack.len();
+                    self.stack.swap(len - 1, len - 2);
+                },
+                OpCode::OpOver => {
+                    let value = self.peek(1);
+                    self.push(value);
+                },
                 OpCode::OpGetLocal => {
                     let slot = self.read_byte() as usize;
                     let slot_offset = self.curr_frame().slots;
@@ -215,89 +287,48 @@ impl VM {
                     let slot_offset = self.curr_frame().slots;
                     self.stack[slot_offset + slot] = self.peek(0);
                 },
-                OpCode::OpSetConstLocal => {
-                    println!("Const variable already defined in this scope");
-                    return InterpretResult::InterpretCompilerError;
-                }
                 OpCode::OpGetGlobal => {
                     let name: String = self.read_constant().to_string();
-                    let const_name = name.clone() + "const";
-                    let value: Value;
-                    match self.globals.get(&name) {
-                        Some(val) => { 
-                            value = val.clone();
-                            ()
-                        },
+                    let value: Value = match self.globals.get(&name) {
+                        Some(global) => global.value.clone(),
                         None => {
-                            match self.globals.get(&const_name){
-                                Some(val) => {
-                                    value = val.clone();
-                                    ()
-                                }
-                                None => {
-                                    println!("Undefined variable {}.", name);
-                                    return InterpretResult::InterpretRuntimeError; 
-                                }
-                            } 
+                            if let Some(reason) = self.unsent_globals.get(&name) {
+                                let message = format!("{} isn't available in a worker. {}", name, reason);
+                                self.runtime_error(&message);
+                                return InterpretResult::InterpretRuntimeError;
+                            }
+                            println!("Undefined variable {}.", name);
+                            return InterpretResult::InterpretRuntimeError; 
                         }
-                    }
+                    };
                     self.push(value);
                 },
                 OpCode::OpDefineGlobal => { // 21.2
                     let name = self.read_constant().to_string();
-                    let const_name = name.clone() + "const";
-                    match self.globals.get(&const_name){
-                        Some(_val) => {
-                            println!("Const variable already defined {}", name);
-                            return InterpretResult::InterpretCompilerError;
-                        },
-                        None => {
-                            let peeked_value = self.peek(0).clone(); 
-                            self.globals.insert(name, peeked_value); 
-                            self.pop();
-                        }
-
+                    if !self.define_global(name, false) {
+                        return InterpretResult::InterpretRuntimeError;
                     }
                 },
                 OpCode::OpSetGlobal => {
                     let name: String = self.read_constant().to_string();
-                    let const_name = name.clone() + "const";
-                    match self.globals.get(&name) {
-                        Some(_val) => {
-                            let insert_value = self.peek(0);
-                            self.globals.insert(name, insert_value).unwrap();
-                            ()
+                    let insert_value = self.peek(0);
+                    match self.globals.get_mut(&name) {
+                        Some(global) if global.constant => {
+                            self.runtime_error(&format!("Can't assign to const variable {}.", name));
+                            return InterpretResult::InterpretRuntimeError;
                         },
+                        Some(global) => global.value = insert_value,
                         None => {
-                            match self.globals.get(&const_name){
-                                Some(_val) => {
-                                    println!("Const variable already defined {}", name);
-                                    return InterpretResult::InterpretCompilerError;
-                                },
-                                None => {
-                                    println!("Undefined variable {}", name);
-                                    return InterpretResult::InterpretRuntimeError;
-                                }
-                            }
+                            println!("Undefined variable {}", name);
+                            return InterpretResult::InterpretRuntimeError;
                         }
                     }
                 },
                 OpCode::OpDefineConstGlobal => {
                     let name = self.read_constant().to_string();
-                    let const_name = name.clone() + "const";
-                    match self.globals.get(&const_name){
-                        Some(_val) => {
-                            println!("Const variable already defined {}", name);
-                            return InterpretResult::InterpretCompilerError;
-                        },
-                        None => {
-                            let peeked_value = self.peek(0).clone(); 
-                            self.globals.insert(const_name, peeked_value); 
-                            self.pop();
-                        }
-
+                    if !self.define_global(name, true) {
+                        return InterpretResult::InterpretRuntimeError;
                     }
-                    
                 },
                 OpCode::OpEqual => {
                     let b : Value = self.pop();
@@ -347,6 +378,36 @@ impl VM {
                     print!("{}",self.pop());
                     println!("");
                 },
+                OpCode::OpAssert => {
+                    let text: String = self.read_constant().into();
+                    let message: Value = self.pop();
+                    let condition: Value = self.pop();
+                    if condition.is_falsey() {
+                        let error_message = match message {
+                            Value::Nil => text,
+                            _ => format!("{} ({})", text, message)
+                        };
+                        self.runtime_error(&error_message);
+                        return InterpretResult::InterpretRuntimeError;
+                    }
+                },
+                OpCode::OpDefer => {
+                    let index = self.read_byte_u8();
+                    let mut deferred = self.curr_frame().deferred.borrow_mut();
+                    if !deferred.contains(&index) {
+                        deferred.push(index);
+                    }
+                },
+                // Pushes whether the defer statement ran, and forgets it so its code only runs once
+                OpCode::OpDeferred => {
+                    let index = self.read_byte_u8();
+                    let ran = {
+                        let mut deferred = self.curr_frame().deferred.borrow_mut();
+                        let position = deferred.iter().position(|ran| *ran == index);
+                        position.map(|position| deferred.remove(position)).is_some()
+                    };
+                    self.push(Value::Bool(ran));
+                },
                 OpCode::OpNegate => {
                     if !is_number(self.peek(0)){
                         self.runtime_error("Operand must be a number.");
@@ -380,35 +441,251 @@ impl VM {
                         return InterpretResult::InterpretRuntimeError
                     }
                 },
+                OpCode::OpTailCall => {
+                    let arg_count = self.read_byte() as usize;
+                    let callee = self.peek(arg_count);
+                    if !self.tail_call_value(callee, arg_count){
+                        return InterpretResult::InterpretRuntimeError
+                    }
+                },
+                OpCode::OpInvoke => {
+                    let name: String = self.read_constant().into();
+                    let arg_count = self.read_byte_u8() as usize;
+                    if !self.invoke(&name, arg_count){
+                        return InterpretResult::InterpretRuntimeError
+                    }
+                },
+                OpCode::OpGetProperty => {
+                    let name: String = self.read_constant().into();
+                    let field = match self.peek(0) {
+                        Value::Record(_record) => _record.get(&name).cloned(),
+                        other => {
+                            self.runtime_error(&format!("Only records have fields, not {}.", other.type_name()));
+                            return InterpretResult::InterpretRuntimeError
+                        }
+                    };
+                    match field {
+                        Some(value) => {
+                            self.pop();
+                            self.push(value);
+                        },
+                        None => {
+                            self.runtime_error(&format!("Undefined field '{}'.", name));
+                            return InterpretResult::InterpretRuntimeError
+                        }
+                    }
+                },
                 OpCode::OpReturn => {
                     let result = self.pop();
                     let prev_frame = self.frames.pop().unwrap();
-                    if self.frames.len() == 0 {
-                        self.pop();
+                    self.stack.truncate(prev_frame.slots);
+                    self.stack.push(result);
+
+                    // The result of the outermost function is left on the stack for whoever called run()
+                    if self.frames.len() == base_frames {
                         return InterpretResult::InterpretOk;
                     }
-
-                    self.stack.truncate(prev_frame.slots - 1);
-                    self.stack.push(result)
                 }
             }
             
         }
     }
 
+    // Sets the options the compiler uses for the following calls to interpret()
+    pub fn set_compiler_options(&mut self, options: CompilerOptions) {
+        self.compiler.options = options;
+    }
+
     // Entry function for the VM
     pub fn interpret(&mut self, source: String) -> InterpretResult {
         
+        self.compiler.predefined_globals = self.globals.keys().cloned().collect();
         let function: Option<Function> = self.compiler.compile(source);
         if function == None {return InterpretResult::InterpretCompilerError;}
 
-        let function: Function = function.unwrap();
+        let function: Arc<Function> = Arc::new(function.unwrap());
         self.push(Value::Fun(function.clone()));
         self.call(function, 0);
         
-        let result = self.run();  
-         
-        result    
+        let result = self.run(0);  
+        if result != InterpretResult::InterpretOk {
+            return result;
+        }
+        self.pop();
+
+        let result = self.run_timers();
+        if result != InterpretResult::InterpretOk {
+            return result;
+        }
+        self.join_workers()
+    }
+
+    /**
+     * Calls a function from Rust code, like the event loop or a native, and returns its result
+     * Returns None if there was a runtime error, which has already been reported
+     */
+    pub fn call_function(&mut self, callee: Value, args: Vec<Value>) -> Option<Value> {
+        let frame_count = self.frames.len();
+        let arg_count = args.len();
+        self.push(callee.clone());
+        self.stack.extend(args);
+        if !self.call_value(callee, arg_count) {
+            return None;
+        }
+        // A native returns right away, a Lox function runs until its frame returns
+        if self.frames.len() > frame_count && self.run(frame_count) != InterpretResult::InterpretOk {
+            return None;
+        }
+        Some(self.pop())
+    }
+
+    /**
+     * Starts a new VM on another thread, which calls the function with the arguments
+     * The new VM gets a copy of every global that can be sent to another thread, and reports an error when
+     * its code reads one of the others
+     */
+    pub fn spawn(&mut self, function: Value, args: &[Value]) -> Result<Value, String> {
+        let function = function.to_sendable()?;
+        let args = args.iter().map(|arg| arg.to_sendable()).collect::<Result<Vec<Value>, String>>()?;
+        let mut globals: Vec<(String, Global)> = Vec::new();
+        let mut unsent_globals: HashMap<String, String> = HashMap::new();
+        for (name, global) in self.globals.iter() {
+            match global.value.to_sendable() {
+                Ok(value) => globals.push((name.clone(), Global { value, constant: global.constant })),
+                Err(reason) => { unsent_globals.insert(name.clone(), reason); },
+            }
+        }
+        let virtual_clock = self.virtual_time.is_some();
+
+        let handle = thread::spawn(move || {
+            let mut vm = VM::new();
+            vm.globals.extend(globals);
+            vm.unsent_globals = unsent_globals;
+            if virtual_clock {
+                vm.use_virtual_clock();
+            }
+            vm.run_worker(function, args)
+        });
+
+        let worker = Arc::new(Worker::new(handle));
+        self.workers.push(worker.clone());
+        Ok(Value::Worker(worker))
+    }
+
+    /**
+     * Runs the function of a worker, along with its timers and its own workers
+     * Runtime errors are reported by the worker, the thread that joins it only learns that it failed
+     */
+    fn run_worker(&mut self, function: Value, args: Vec<Value>) -> Result<Value, String> {
+        let failed = "Worker stopped with an error.".to_string();
+        let result = self.call_function(function, args).ok_or(failed.clone())?;
+        if self.run_timers() != InterpretResult::InterpretOk || self.join_workers() != InterpretResult::InterpretOk {
+            return Err(failed);
+        }
+        result.to_sendable().map_err(|message| {
+            self.runtime_error(&message);
+            failed
+        })
+    }
+
+    /**
+     * Waits for every worker started by this VM
+     */
+    fn join_workers(&mut self) -> InterpretResult {
+        let mut result = InterpretResult::InterpretOk;
+        for worker in std::mem::take(&mut self.workers) {
+            if worker.join().is_err() {
+                result = InterpretResult::InterpretRuntimeError;
+            }
+        }
+        result
+    }
+
+    /**
+     * The event loop, which runs the scheduled callbacks in order of when they are due until no timers remain
+     */
+    fn run_timers(&mut self) -> InterpretResult {
+        while let Some(index) = self.next_timer() {
+            let due = self.timers[index].due;
+            match self.virtual_time {
+                Some(_) => self.virtual_time = Some(due),
+                None => {
+                    let wait = due - self.now();
+                    if wait > 0.0 {
+                        thread::sleep(Duration::from_secs_f64(wait / 1000.0));
+                    }
+                }
+            }
+
+            let callback = self.timers[index].callback.clone();
+            match self.timers[index].interval {
+                Some(interval) => self.timers[index].due += interval,
+                None => { self.timers.remove(index); }
+            }
+
+            if self.call_function(callback, Vec::new()).is_none() {
+                return InterpretResult::InterpretRuntimeError;
+            }
+        }
+        InterpretResult::InterpretOk
+    }
+
+    /**
+     * Returns the index of the timer that is due first, timers due at the same time run in the order they were created
+     */
+    fn next_timer(&self) -> Option<usize> {
+        self.timers.iter().enumerate()
+            .min_by(|(_, a), (_, b)| a.due.total_cmp(&b.due).then(a.id.cmp(&b.id)))
+            .map(|(index, _)| index)
+    }
+
+    /**
+     * Schedules a callback to run after the delay in milliseconds, and again every delay if it repeats
+     * Returns the id of the timer
+     */
+    pub fn schedule(&mut self, callback: Value, delay: f64, repeat: bool) -> usize {
+        let id = self.next_timer_id;
+        self.next_timer_id += 1;
+        self.timers.push(Timer {
+            id,
+            due: self.now() + delay,
+            interval: if repeat { Some(delay) } else { None },
+            callback,
+        });
+        id
+    }
+
+    /**
+     * Removes the timer with the given id, returns false if there was no such timer
+     */
+    pub fn cancel_timer(&mut self, id: usize) -> bool {
+        let count = self.timers.len();
+        self.timers.retain(|timer| timer.id != id);
+        self.timers.len() != count
+    }
+
+    /**
+     * Makes the VM use a virtual clock, so timers run without waiting
+     */
+    pub fn use_virtual_clock(&mut self) {
+        self.virtual_time = Some(0.0);
+    }
+
+    /**
+     * Returns the time of the virtual clock in milliseconds, or None if the VM uses the real clock
+     */
+    pub fn virtual_time(&self) -> Option<f64> {
+        self.virtual_time
+    }
+
+    /**
+     * Returns the time in milliseconds since the VM started, or the time of the virtual clock
+     */
+    fn now(&self) -> f64 {
+        match self.virtual_time {
+            Some(time) => time,
+            None => self.start.elapsed().as_secs_f64() * 1000.0
+        }
     }
 
     /**
@@ -441,7 +718,7 @@ impl VM {
     /**
      * Initializes the next CallFrame on the stack.
      */
-    pub fn call(&mut self, function: Function, arg_count: usize) -> bool{
+    pub fn call(&mut self, function: Arc<Function>, arg_count: usize) -> bool{
         if arg_count != function.arity {
             self.runtime_error(&format!("Expected {} arguments but got {}", function.arity, arg_count));
             return false;
@@ -452,20 +729,12 @@ impl VM {
             return false;
         }
 
-        let fun_name = function.clone().name;
-        if fun_name == None{
-            self.frames.push( CallFrame {
-                function: function,
-                ip: RefCell::new(0), 
-                slots: self.stack.len() - arg_count as usize - 1
-            });
-            return true;
-        }
-
+        // Slot 0 of the frame is the function itself, followed by its arguments
         self.frames.push( CallFrame {
             function: function,
             ip: RefCell::new(0), 
-            slots: self.stack.len() - arg_count as usize 
+            slots: self.stack.len() - arg_count as usize - 1,
+            deferred: RefCell::new(Vec::new()),
         });
         return true;
         
@@ -477,11 +746,34 @@ impl VM {
     pub fn call_value(&mut self, callee: Value, arg_count: usize) -> bool{
         match callee{
             Value::Fun(_function) => return self.call(_function, arg_count),
-            Value::Native(_native_fun) => {
-                let stack_len = self.stack.len();
-                let result = _native_fun.fun_call(arg_count, &self.stack[stack_len - arg_count..stack_len]);
-                self.stack.truncate(stack_len - arg_count + 1);
-                self.push(result);
+            Value::Native(_native) => {
+                if _native.arity.is_some_and(|arity| arity != arg_count) {
+                    self.runtime_error(&format!("Expected {} arguments but got {}", _native.arity.unwrap(), arg_count));
+                    return false;
+                }
+                // Take the arguments and the callee off the stack, since the native gets the whole VM
+                let args: Vec<Value> = self.stack.split_off(self.stack.len() - arg_count);
+                self.pop();
+                match _native.function.fun_call(self, arg_count, &args) {
+                    Ok(result) => {
+                        self.push(result);
+                        true
+                    },
+                    Err(NativeError::Message(message)) => {
+                        self.runtime_error(&message);
+                        false
+                    },
+                    Err(NativeError::Reported) => false
+                }
+            }
+            Value::RecordType(_record_type) => {
+                if _record_type.fields.len() != arg_count {
+                    self.runtime_error(&format!("Expected {} arguments but got {}", _record_type.fields.len(), arg_count));
+                    return false;
+                }
+                let values: Vec<Value> = self.stack.split_off(self.stack.len() - arg_count);
+                self.pop();
+                self.push(Value::Record(Arc::new(Record { record_type: _record_type, values })));
                 true
             }
             _ => {
@@ -491,6 +783,62 @@ impl VM {
         }
     }
 
+    /**
+     * Calls the callee in place of the current function, reusing its CallFrame and its stack window
+     * Only Lox functions with the right number of arguments are tail called, anything else is called as usual
+     */
+    pub fn tail_call_value(&mut self, callee: Value, arg_count: usize) -> bool{
+        let function = match callee {
+            Value::Fun(_function) if _function.arity == arg_count => _function,
+            _ => return self.call_value(callee, arg_count)
+        };
+
+        // The callee and its arguments take the place of the current function and its locals
+        let prev_frame = self.frames.pop().unwrap();
+        let callee_and_args: Vec<Value> = self.stack.split_off(self.stack.len() - arg_count - 1);
+        self.stack.truncate(prev_frame.slots);
+        self.stack.extend(callee_and_args);
+
+        self.call(function, arg_count)
+    }
+
+    /**
+     * Calls a method of a built-in type on the receiver, which sits below the arguments on the stack
+     */
+    pub fn invoke(&mut self, name: &str, arg_count: usize) -> bool{
+        let receiver = self.peek(arg_count);
+
+        // A record field holding a function is called like a method, without the record as an argument
+        if let Value::Record(_record) = &receiver {
+            if let Some(field) = _record.get(name) {
+                let stack_len = self.stack.len();
+                self.stack[stack_len - arg_count - 1] = field.clone();
+                return self.call_value(field.clone(), arg_count);
+            }
+        }
+
+        let method = match self.methods.get(receiver.type_name()).and_then(|methods| methods.get(name)) {
+            Some(method) => *method,
+            None => {
+                self.runtime_error(&format!("Undefined method '{}' for {}.", name, receiver.type_name()));
+                return false;
+            }
+        };
+
+        let stack_len = self.stack.len();
+        match method(&receiver, &self.stack[stack_len - arg_count..stack_len]) {
+            Ok(result) => {
+                self.stack.truncate(stack_len - arg_count - 1);
+                self.push(result);
+                true
+            },
+            Err(message) => {
+                self.runtime_error(&message);
+                false
+            }
+        }
+    }
+
     /**
      * Concatenates two strings into one
      */
@@ -503,10 +851,44 @@ impl VM {
     }
 
     /**
-     * Inserts the native function into the global variables
+     * Defines the global with the value on top of the stack
+     * A const global can't be defined again, which the compiler can't always see ahead of time
+     */
+    fn define_global(&mut self, name: String, constant: bool) -> bool {
+        if let Some(Global { constant: true, .. }) = self.globals.get(&name) {
+            self.runtime_error(&format!("Const variable {} is already defined.", name));
+            return false;
+        }
+        let value = self.pop();
+        self.globals.insert(name, Global { value, constant });
+        true
+    }
+
+    /**
+     * Returns the global variables
+     */
+    pub fn globals(&self) -> &HashMap<String, Global> {
+        &self.globals
+    }
+
+    /**
+     * Adds a method to the method table of a built-in type
      */
-    fn define_native(&mut self, name: String, function: &Rc<dyn NativeFn>){
-        self.globals.insert(name, Value::Native(Rc::clone(function)));
+    fn define_method(&mut self, type_name: &'static str, name: &'static str, method: NativeMethod){
+        self.methods.entry(type_name).or_default().insert(name, method);
+    }
+
+    /**
+     * Inserts the native function into the global variables, along with its documentation
+     */
+    fn define_native(&mut self, name: &str, arity: Option<usize>, doc: &str, function: Arc<dyn NativeFn>){
+        let native = Native {
+            name: name.to_string(),
+            arity,
+            doc: Some(doc.to_string()),
+            function,
+        };
+        self.globals.insert(name.to_string(), Global { value: Value::Native(native), constant: false });
     }
 }
 